@@ -4,19 +4,35 @@
 //! entity interacting with the Cincinnati upstream server.
 //! It periodically tries to fetch a graph of updates, picking
 //! the greatest one available.
+//! Graphs can optionally be verified against a detached signature.
+//! Releases in the local blocklist are never offered.
 
-use crate::update_agent::Identity;
+mod signature;
+pub(crate) use signature::Keyring;
+
+use crate::config::CincinnatiConfig;
+use crate::http;
+use crate::metrics;
+use crate::update_agent::{Blocklist, Identity};
 use actix::prelude::*;
 use failure::{Error, Fallible};
+use futures::future;
 use futures::prelude::*;
 use lazy_static::lazy_static;
-use reqwest::r#async as asynchro;
-use reqwest::Url;
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
 use std::sync;
+use url::Url;
 
 /// Cincinnati graph API path endpoint (v1).
 static V1_GRAPH_PATH: &str = "v1/graph";
 
+/// Detached graph signature path endpoint (v1).
+static V1_GRAPH_SIGNATURE_PATH: &str = "v1/graph.sig";
+
+/// Maximum size of a graph signature response, in bytes.
+static MAX_SIGNATURE_BYTES: u64 = 4096;
+
 lazy_static! {
     pub(crate) static ref CONFIGURED: sync::RwLock<Option<CincinnatiClient>> =
         sync::RwLock::default();
@@ -26,9 +42,24 @@ lazy_static! {
 ///
 /// This overwrite the global configuration for `CincinnatiClient`.
 /// It is called at least once at initialization time.
-pub(crate) fn configure(base_url: reqwest::Url, identity: Identity) -> Fallible<()> {
-    let endpoint = base_url.join(V1_GRAPH_PATH)?;
-    let scanner = CincinnatiClient { endpoint, identity };
+pub(crate) fn configure(
+    cfg: CincinnatiConfig,
+    identity: Identity,
+    blocklist: Blocklist,
+) -> Fallible<()> {
+    let endpoint = cfg.base_url.join(V1_GRAPH_PATH)?;
+    let signature_endpoint = cfg.base_url.join(V1_GRAPH_SIGNATURE_PATH)?;
+    let user_agent = identity.user_agent();
+    let client = cfg.client.build(&cfg.tls, &user_agent)?;
+    let scanner = CincinnatiClient {
+        blocklist,
+        client,
+        endpoint,
+        identity,
+        keyring: cfg.keyring,
+        max_graph_bytes: cfg.max_graph_bytes,
+        signature_endpoint,
+    };
     let mut static_cfg = CONFIGURED.try_write().unwrap();
     *static_cfg = Some(scanner);
     Ok(())
@@ -37,8 +68,16 @@ pub(crate) fn configure(base_url: reqwest::Url, identity: Identity) -> Fallible<
 /// Main actor for interacting with Cincinnati server.
 #[derive(Clone, Debug)]
 pub struct CincinnatiClient {
+    /// Local blocklist of bad releases.
+    blocklist: Blocklist,
+    /// Long-lived HTTP client, shared across requests.
+    client: http::Client,
     endpoint: Url,
     identity: Identity,
+    /// Trusted keys for graph signatures, if verification is enabled.
+    keyring: Option<Keyring>,
+    max_graph_bytes: u64,
+    signature_endpoint: Url,
 }
 
 impl Default for CincinnatiClient {
@@ -60,25 +99,131 @@ impl Supervised for CincinnatiClient {}
 impl SystemService for CincinnatiClient {}
 
 /// CincinnatiClient request: fetch a graph of updates.
-pub(crate) struct FetchGraph {}
+pub(crate) struct FetchGraph {
+    /// Stream to look for updates in.
+    pub(crate) stream: String,
+    /// Version of the booted release.
+    pub(crate) current: String,
+}
 
 impl Message for FetchGraph {
     type Result = Result<Option<libcincinnati::Release>, Error>;
 }
 
 impl Handler<FetchGraph> for CincinnatiClient {
-    type Result = Box<Future<Item = Option<libcincinnati::Release>, Error = Error>>;
+    type Result = Box<dyn Future<Item = Option<libcincinnati::Release>, Error = Error>>;
 
-    fn handle(&mut self, _msg: FetchGraph, _ctx: &mut Self::Context) -> Self::Result {
-        let endpoint = self.endpoint.clone();
-        let identity = self.identity.clone();
+    fn handle(&mut self, msg: FetchGraph, _ctx: &mut Self::Context) -> Self::Result {
+        let mut params: HttpParams = self.identity.clone().into();
+        params.stream = msg.stream;
+        params.current_version = msg.current;
+        let blocked = match self.blocklist.versions() {
+            Ok(b) => b,
+            Err(e) => return Box::new(future::err(e)),
+        };
 
         // Ask remote cincinnati server for available updates.
-        let next_release = fetch_cincinnati_next(endpoint, identity.into());
+        let next_release = fetch_cincinnati_next(self.clone(), params, blocked);
         Box::new(next_release)
     }
 }
 
+/// CincinnatiClient request: re-evaluate a staged release against the graph.
+pub(crate) struct RefreshStaged {
+    /// Stream to look for updates in.
+    pub(crate) stream: String,
+    /// Version of the booted release.
+    pub(crate) current: String,
+    /// Version of the staged release.
+    pub(crate) staged: String,
+}
+
+impl Message for RefreshStaged {
+    type Result = Result<StagedRefresh, Error>;
+}
+
+impl Handler<RefreshStaged> for CincinnatiClient {
+    type Result = Box<dyn Future<Item = StagedRefresh, Error = Error>>;
+
+    fn handle(&mut self, msg: RefreshStaged, _ctx: &mut Self::Context) -> Self::Result {
+        let mut params: HttpParams = self.identity.clone().into();
+        params.stream = msg.stream;
+        params.current_version = msg.current;
+        let blocked = match self.blocklist.versions() {
+            Ok(b) => b,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        let refresh = fetch_staged_refresh(self.clone(), params, msg.staged, blocked);
+        Box::new(refresh)
+    }
+}
+
+/// A staged release, re-evaluated against the current graph.
+#[derive(Clone, Debug)]
+pub(crate) enum StagedRefresh {
+    /// Staged release is still the update target.
+    Current,
+    /// Staged release is superseded by this strictly newer one.
+    Superseded(libcincinnati::Release),
+    /// Staged release is not offered anymore (or was blocklisted).
+    Pulled,
+}
+
+/// CincinnatiClient request: find the entry release for another stream.
+pub(crate) struct FetchStreamEntry {
+    /// Stream to rebase to.
+    pub(crate) stream: String,
+    /// Version of the booted release.
+    pub(crate) current: String,
+}
+
+impl Message for FetchStreamEntry {
+    type Result = Result<Option<libcincinnati::Release>, Error>;
+}
+
+impl Handler<FetchStreamEntry> for CincinnatiClient {
+    type Result = Box<dyn Future<Item = Option<libcincinnati::Release>, Error = Error>>;
+
+    fn handle(&mut self, msg: FetchStreamEntry, _ctx: &mut Self::Context) -> Self::Result {
+        let mut params: HttpParams = self.identity.clone().into();
+        params.stream = msg.stream;
+        params.current_version = msg.current;
+        let blocked = match self.blocklist.versions() {
+            Ok(b) => b,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        let entry = fetch_stream_entry(self.clone(), params, blocked);
+        Box::new(entry)
+    }
+}
+
+/// CincinnatiClient request: find a release by version in a stream.
+pub(crate) struct FindRelease {
+    pub(crate) version: String,
+    pub(crate) stream: String,
+}
+
+impl Message for FindRelease {
+    type Result = Result<Option<libcincinnati::Release>, Error>;
+}
+
+impl Handler<FindRelease> for CincinnatiClient {
+    type Result = Box<dyn Future<Item = Option<libcincinnati::Release>, Error = Error>>;
+
+    fn handle(&mut self, msg: FindRelease, _ctx: &mut Self::Context) -> Self::Result {
+        let mut params: HttpParams = self.identity.clone().into();
+        params.stream = msg.stream;
+        let blocked = match self.blocklist.versions() {
+            Ok(b) => b,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let release = fetch_release(self.clone(), params, msg.version, blocked);
+        Box::new(release)
+    }
+}
+
 #[derive(Clone, Debug, Serialize)]
 pub(crate) struct HttpParams {
     pub(crate) current_version: String,
@@ -97,7 +242,8 @@ impl From<Identity> for HttpParams {
         };
         Self {
             current_version: identity.current_version,
-            stream: identity.stream,
+            // Requests always set the stream they target.
+            stream: identity.stream.unwrap_or_default(),
             arch: identity.arch,
             platform: identity.platform,
             throttle_permille,
@@ -109,55 +255,22 @@ impl From<Identity> for HttpParams {
 ///
 /// Request a graph of releases from Cincinnati server, extract all
 /// available updates reachable from the current version, then pick
-/// up the greatest one.
+/// up the greatest one which is not blocklisted.
 fn fetch_cincinnati_next(
-    endpoint: reqwest::Url,
+    cincinnati: CincinnatiClient,
     params: HttpParams,
+    blocked: BTreeSet<String>,
 ) -> impl Future<Item = Option<libcincinnati::Release>, Error = Error> {
-    trace!("cincinnati client parameters: {:?}", params);
-    trace!("GET to remote graph endpoint: {:?}", endpoint);
-
-    // Request cincinnati graph with client-specific parameters.
-    let req = asynchro::Client::new().get(endpoint).query(&params).send();
-
-    // Ensure response is positive.
-    let resp = req
-        .and_then(|resp| resp.error_for_status())
-        .map_err(|err| {
-            error!("{}", err);
-            err
-        })
-        .from_err();
+    let current = params.current_version.clone();
+    let verified = fetch_graph_content(cincinnati, params);
 
     // Parse a cincinnati graph from JSON.
-    let graph = resp
-        .inspect(|resp| trace!("graph response: {:#?}", resp))
-        .and_then(|mut resp| resp.json::<libcincinnati::Graph>())
-        .from_err();
-
-    // Extract all available updates reachable from current release.
-    let current = params.current_version.clone();
-    let updates = graph
-        .and_then(move |graph| {
-            trace!("looking for current release '{}' in graph", current);
-            let release_id = graph
-                .find_by_version(&current)
-                .ok_or_else(|| format_err!("current version '{}' not found in graph", current))?;
-
-            let next_releases = graph
-                .next_releases(&release_id)
-                .cloned()
-                .collect::<Vec<_>>();
-            Ok(next_releases)
-        })
-        .inspect(|next_rels| trace!("found {} valid release-update(s)", next_rels.len()));
+    let graph =
+        verified.and_then(|body| Ok(serde_json::from_slice::<libcincinnati::Graph>(&body)?));
 
     // Pick up the greatest next release available, if any.
-    updates
-        .and_then(|ups| {
-            // TODO(lucab): add stable order, then pick up the greatest.
-            Ok(ups.first().cloned())
-        })
+    graph
+        .and_then(move |graph| next_update(&graph, &current, &blocked))
         .inspect(|release| match release {
             Some(r) => info!(
                 "available updates found, selecting '{}' for next update",
@@ -166,3 +279,298 @@ fn fetch_cincinnati_next(
             None => trace!("no next release"),
         })
 }
+
+/// Fetch a graph from Cincinnati, and re-evaluate a staged release against it.
+fn fetch_staged_refresh(
+    cincinnati: CincinnatiClient,
+    params: HttpParams,
+    staged: String,
+    blocked: BTreeSet<String>,
+) -> impl Future<Item = StagedRefresh, Error = Error> {
+    let current = params.current_version.clone();
+
+    fetch_graph_content(cincinnati, params)
+        .and_then(|body| Ok(serde_json::from_slice::<libcincinnati::Graph>(&body)?))
+        .and_then(move |graph| refresh_staged(&graph, &current, &staged, &blocked))
+}
+
+/// Re-evaluate a staged release against a graph.
+///
+/// The staged release is pulled if it is not offered as an update from the
+/// current release anymore, or blocklisted. It is superseded only by a
+/// strictly newer update.
+fn refresh_staged(
+    graph: &libcincinnati::Graph,
+    current: &str,
+    staged: &str,
+    blocked: &BTreeSet<String>,
+) -> Fallible<StagedRefresh> {
+    if is_blocked(blocked, staged) {
+        return Ok(StagedRefresh::Pulled);
+    }
+
+    // Pulled releases lose their incoming edges, but usually stay in the graph.
+    let release_id = graph
+        .find_by_version(current)
+        .ok_or_else(|| format_err!("current version '{}' not found in graph", current))?;
+    if !graph
+        .next_releases(&release_id)
+        .any(|r| r.version() == staged)
+    {
+        return Ok(StagedRefresh::Pulled);
+    }
+
+    let refresh = match next_update(graph, current, blocked)? {
+        Some(ref r) if compare_versions(r.version(), staged) == Ordering::Greater => {
+            StagedRefresh::Superseded(r.clone())
+        }
+        _ => StagedRefresh::Current,
+    };
+    Ok(refresh)
+}
+
+/// Find the greatest update reachable from the current release, which is not blocklisted.
+fn next_update(
+    graph: &libcincinnati::Graph,
+    current: &str,
+    blocked: &BTreeSet<String>,
+) -> Fallible<Option<libcincinnati::Release>> {
+    trace!("looking for current release '{}' in graph", current);
+    let release_id = graph
+        .find_by_version(current)
+        .ok_or_else(|| format_err!("current version '{}' not found in graph", current))?;
+
+    let next_releases = graph
+        .next_releases(&release_id)
+        .filter(|r| !is_blocked(blocked, r.version()))
+        .collect::<Vec<_>>();
+    trace!("found {} valid release-update(s)", next_releases.len());
+
+    let next = next_releases
+        .into_iter()
+        .max_by(|a, b| compare_versions(a.version(), b.version()))
+        .cloned();
+    Ok(next)
+}
+
+/// Graph nodes, as served by Cincinnati.
+#[derive(Debug, Deserialize)]
+struct GraphNodes {
+    nodes: Vec<libcincinnati::ConcreteRelease>,
+}
+
+/// Fetch the entry release for a stream from Cincinnati.
+///
+/// Request the graph of the target stream, and pick up its greatest
+/// release which is not blocklisted as the rebase target.
+fn fetch_stream_entry(
+    cincinnati: CincinnatiClient,
+    params: HttpParams,
+    blocked: BTreeSet<String>,
+) -> impl Future<Item = Option<libcincinnati::Release>, Error = Error> {
+    let stream = params.stream.clone();
+
+    fetch_graph_content(cincinnati, params)
+        .and_then(|body| Ok(serde_json::from_slice::<GraphNodes>(&body)?))
+        .map(move |graph| {
+            graph
+                .nodes
+                .into_iter()
+                .filter(|node| !is_blocked(&blocked, &node.version))
+                .max_by(|a, b| compare_versions(&a.version, &b.version))
+                .map(libcincinnati::Release::from)
+        })
+        .inspect(move |release| match release {
+            Some(r) => info!(
+                "stream '{}' entry release found, selecting '{}'",
+                stream,
+                r.version()
+            ),
+            None => warn!("no releases found in stream '{}'", stream),
+        })
+}
+
+/// Fetch the release with a given version from Cincinnati, if any and not blocklisted.
+fn fetch_release(
+    cincinnati: CincinnatiClient,
+    params: HttpParams,
+    version: String,
+    blocked: BTreeSet<String>,
+) -> impl Future<Item = Option<libcincinnati::Release>, Error = Error> {
+    if is_blocked(&blocked, &version) {
+        return future::Either::A(future::ok(None));
+    }
+
+    let release = fetch_graph_content(cincinnati, params)
+        .and_then(|body| Ok(serde_json::from_slice::<GraphNodes>(&body)?))
+        .map(move |graph| {
+            graph
+                .nodes
+                .into_iter()
+                .find(|node| node.version == version)
+                .map(libcincinnati::Release::from)
+        });
+    future::Either::B(release)
+}
+
+/// Check whether a release version is blocklisted.
+fn is_blocked(blocked: &BTreeSet<String>, version: &str) -> bool {
+    let found = blocked.contains(version);
+    if found {
+        debug!("skipping blocklisted release '{}'", version);
+    }
+    found
+}
+
+/// Compare two release versions, component-wise.
+///
+/// Numeric components are compared as numbers, others lexically.
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let split = |v: &str| -> Vec<String> { v.split(['.', '-']).map(String::from).collect() };
+    let (left, right) = (split(a), split(b));
+
+    for (l, r) in left.iter().zip(right.iter()) {
+        let ord = match (l.parse::<u64>(), r.parse::<u64>()) {
+            (Ok(ln), Ok(rn)) => ln.cmp(&rn),
+            _ => l.cmp(r),
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+    left.len().cmp(&right.len())
+}
+
+/// Fetch raw graph content from Cincinnati, verifying its signature if required.
+fn fetch_graph_content(
+    cincinnati: CincinnatiClient,
+    params: HttpParams,
+) -> impl Future<Item = Vec<u8>, Error = Error> {
+    trace!("cincinnati client parameters: {:?}", params);
+    trace!("GET to remote graph endpoint: {:?}", cincinnati.endpoint);
+    metrics::record_graph_fetch();
+
+    // Request cincinnati graph with client-specific parameters.
+    let req = cincinnati.client.get(cincinnati.endpoint.clone(), &params);
+
+    // Ensure response is positive.
+    let resp = req.and_then(http::check_status).map_err(|err| {
+        error!("{}", err);
+        err
+    });
+
+    // Read raw graph content, refusing oversized responses.
+    let max_graph_bytes = cincinnati.max_graph_bytes;
+    let body = resp
+        .inspect(|resp| trace!("graph response: {:#?}", resp))
+        .and_then(move |resp| http::read_body_limited(resp, max_graph_bytes));
+
+    // Verify graph signature, if required.
+    body.and_then(move |body| match cincinnati.keyring.clone() {
+        Some(keyring) => {
+            let verify = fetch_signature(&cincinnati, params).and_then(move |signature| {
+                keyring.verify(&body, &signature)?;
+                Ok(body)
+            });
+            future::Either::A(verify)
+        }
+        None => future::Either::B(future::ok(body)),
+    })
+}
+
+/// Fetch the detached signature for a graph request.
+fn fetch_signature(
+    cincinnati: &CincinnatiClient,
+    params: HttpParams,
+) -> impl Future<Item = String, Error = Error> {
+    trace!(
+        "GET to remote graph signature endpoint: {:?}",
+        cincinnati.signature_endpoint
+    );
+
+    cincinnati
+        .client
+        .get(cincinnati.signature_endpoint.clone(), &params)
+        .and_then(http::check_status)
+        .and_then(|resp| http::read_body_limited(resp, MAX_SIGNATURE_BYTES))
+        .and_then(|body| {
+            String::from_utf8(body).map_err(|e| format_err!("invalid signature encoding: {}", e))
+        })
+        .map_err(|err| err.context("failed to fetch graph signature").into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    /// Graph with updates from the first release to each other one.
+    fn graph(releases: &[&str]) -> libcincinnati::Graph {
+        let mut graph = libcincinnati::Graph::default();
+        let mut ids = vec![];
+        for version in releases {
+            let release = testing::concrete_release(version, 'a');
+            ids.push(graph.add_release(release).unwrap());
+        }
+        for to in &ids[1..] {
+            graph.add_edge(&ids[0], to).unwrap();
+        }
+        graph
+    }
+
+    #[test]
+    fn test_refresh_staged() {
+        let none = BTreeSet::new();
+        let blocked: BTreeSet<_> = vec!["30.3".to_string()].into_iter().collect();
+
+        let cases = vec![
+            // Staged release is still the greatest update.
+            (vec!["30.1", "30.2"], "30.2", &none, "current"),
+            // A newer release supersedes the staged one.
+            (
+                vec!["30.1", "30.2", "30.3"],
+                "30.2",
+                &none,
+                "superseded 30.3",
+            ),
+            // Blocklisted releases never supersede the staged one.
+            (vec!["30.1", "30.2", "30.3"], "30.2", &blocked, "current"),
+            // Staged release pulled, while an older update is still offered.
+            (vec!["30.1", "30.2"], "30.4", &none, "pulled"),
+            // Staged release pulled, and no update offered at all.
+            (vec!["30.1"], "30.2", &none, "pulled"),
+            // Staged release blocklisted.
+            (vec!["30.1", "30.2", "30.3"], "30.3", &blocked, "pulled"),
+        ];
+
+        for (releases, staged, blocked, expected) in cases {
+            let refresh = refresh_staged(&graph(&releases), "30.1", staged, blocked).unwrap();
+            let summary = match refresh {
+                StagedRefresh::Current => "current".to_string(),
+                StagedRefresh::Superseded(r) => format!("superseded {}", r.version()),
+                StagedRefresh::Pulled => "pulled".to_string(),
+            };
+            assert_eq!(summary, expected, "staged '{}' in {:?}", staged, releases);
+        }
+    }
+
+    #[test]
+    fn test_refresh_staged_pulled_edge() {
+        // Staged release still in the graph, but not reachable anymore.
+        let mut graph = graph(&["30.1", "30.3"]);
+        graph
+            .add_release(testing::concrete_release("30.2", 'a'))
+            .unwrap();
+        let refresh = refresh_staged(&graph, "30.1", "30.2", &BTreeSet::new()).unwrap();
+        match refresh {
+            StagedRefresh::Pulled => {}
+            _ => panic!("staged release not pulled"),
+        };
+    }
+
+    #[test]
+    fn test_refresh_staged_unknown_current() {
+        let graph = graph(&["30.2", "30.3"]);
+        refresh_staged(&graph, "30.1", "30.2", &BTreeSet::new()).unwrap_err();
+    }
+}
@@ -0,0 +1,162 @@
+//! Detached signatures for Cincinnati graphs.
+//!
+//! Graphs can be signed with ed25519 keys. The signature is served
+//! as base64 text alongside the graph, and it covers the exact bytes
+//! of the graph response body. Trusted public keys are stored as
+//! base64 files (`*.pub`) in a local keyring directory.
+
+use ed25519_dalek::{PublicKey, Signature, Verifier};
+use failure::{Fallible, ResultExt};
+use std::path::{Path, PathBuf};
+
+/// File extension for public keys in the keyring directory.
+static PUBKEY_EXTENSION: &str = "pub";
+
+/// Set of trusted public keys for graph signatures.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct Keyring {
+    /// Keyring directory.
+    pub(crate) dir: PathBuf,
+    /// Files of all loaded keys.
+    pub(crate) key_files: Vec<PathBuf>,
+    /// Trusted public keys.
+    #[serde(skip)]
+    keys: Vec<PublicKey>,
+}
+
+impl Keyring {
+    /// Load all public keys from a keyring directory.
+    pub(crate) fn load(dir: &Path) -> Fallible<Self> {
+        let mut key_files = vec![];
+        let entries =
+            std::fs::read_dir(dir).context(format!("failed to read keyring {:?}", dir))?;
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) == Some(PUBKEY_EXTENSION) {
+                key_files.push(path);
+            }
+        }
+        key_files.sort();
+
+        let mut keys = Vec::with_capacity(key_files.len());
+        for path in &key_files {
+            let content =
+                std::fs::read_to_string(path).context(format!("failed to read {:?}", path))?;
+            let raw = base64::decode(content.trim())
+                .context(format!("invalid base64 content in {:?}", path))?;
+            let key = PublicKey::from_bytes(&raw)
+                .map_err(|e| format_err!("invalid public key in {:?}: {}", path, e))?;
+            trace!("loaded graph signing key {:?}", path);
+            keys.push(key);
+        }
+
+        if keys.is_empty() {
+            bail!("no public keys found in keyring {:?}", dir);
+        }
+
+        let keyring = Self {
+            dir: dir.to_path_buf(),
+            key_files,
+            keys,
+        };
+        Ok(keyring)
+    }
+
+    /// Verify a base64-encoded detached signature over `content`.
+    ///
+    /// The signature is accepted if it matches any trusted key.
+    pub(crate) fn verify(&self, content: &[u8], signature: &str) -> Fallible<()> {
+        let raw = base64::decode(signature.trim()).context("invalid base64 signature")?;
+        let sig =
+            Signature::from_bytes(&raw).map_err(|e| format_err!("invalid signature: {}", e))?;
+
+        for (key, path) in self.keys.iter().zip(self.key_files.iter()) {
+            if key.verify(content, &sig).is_ok() {
+                debug!("graph signature verified with key {:?}", path);
+                return Ok(());
+            }
+        }
+
+        bail!("graph signature does not match any trusted key")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Keypair, SecretKey, Signer};
+
+    /// Deterministic keypair from a seed byte.
+    fn keypair(seed: u8) -> Keypair {
+        let secret = SecretKey::from_bytes(&[seed; 32]).unwrap();
+        let public = PublicKey::from(&secret);
+        Keypair { secret, public }
+    }
+
+    fn write_key(dir: &Path, name: &str, key: &Keypair) {
+        let content = base64::encode(key.public.as_bytes());
+        std::fs::write(dir.join(name), content).unwrap();
+    }
+
+    fn sign(key: &Keypair, content: &[u8]) -> String {
+        base64::encode(&key.sign(content).to_bytes()[..])
+    }
+
+    #[test]
+    fn test_verify_trusted_key() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let key = keypair(1);
+        write_key(dir, "graph.pub", &key);
+        // Files without the key extension are ignored.
+        std::fs::write(dir.join("README"), "not a key").unwrap();
+
+        let keyring = Keyring::load(dir).unwrap();
+        assert_eq!(keyring.key_files, vec![dir.join("graph.pub")]);
+
+        let graph = br#"{"nodes":[],"edges":[]}"#;
+        keyring.verify(graph, &sign(&key, graph)).unwrap();
+        // Signatures only cover the exact content.
+        keyring
+            .verify(br#"{"nodes":[]}"#, &sign(&key, graph))
+            .unwrap_err();
+        keyring.verify(graph, "not base64!").unwrap_err();
+    }
+
+    #[test]
+    fn test_verify_wrong_key() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        write_key(dir, "graph.pub", &keypair(1));
+
+        let keyring = Keyring::load(dir).unwrap();
+        let graph = br#"{"nodes":[],"edges":[]}"#;
+        keyring
+            .verify(graph, &sign(&keypair(2), graph))
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_load_malformed_key() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        write_key(dir, "good.pub", &keypair(1));
+        std::fs::write(dir.join("bad.pub"), "not base64!").unwrap();
+        Keyring::load(dir).unwrap_err();
+
+        // Valid base64, but not a valid key length.
+        std::fs::write(dir.join("bad.pub"), base64::encode(&[0u8; 7])).unwrap();
+        Keyring::load(dir).unwrap_err();
+
+        std::fs::remove_file(dir.join("bad.pub")).unwrap();
+        Keyring::load(dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_empty_keyring() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        Keyring::load(dir).unwrap_err();
+        Keyring::load(&dir.join("missing")).unwrap_err();
+    }
+}
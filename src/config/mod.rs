@@ -1,29 +1,252 @@
-/// Configuration parsing and validation.
-///
-/// This module contains three logical entities:
-///  * Snippets: single configuration files, holding a subset of configuration entries.
-///  * Inputs: configuration snippets merged, but not yet validated.
-///  * AgentConfig: validated configuration for the update agent.
+//! Configuration parsing and validation.
+//!
+//! This module contains three logical entities:
+//!  * Snippets: single configuration files, holding a subset of configuration entries.
+//!  * Inputs: configuration snippets merged, but not yet validated.
+//!  * AgentConfig: validated configuration for the update agent.
 
 mod inputs;
 mod snippets;
 
-pub(crate) use crate::config::inputs::{IdentityInput, StratHttpInput, UpdateConfig};
-use crate::update_agent::Identity;
+use crate::cincinnati::Keyring;
+pub(crate) use crate::config::inputs::{
+    AgentInput, ClientInput, IdentityInput, StratHttpInput, TlsInput, UpdateConfig,
+};
+use crate::http::{ClientConfig, TlsConfig};
+use crate::update_agent::{Blocklist, Identity, Scheduler, StateFile};
 use crate::strategy;
 use failure::{Fallible, ResultExt};
 
+/// Default maximum size of a Cincinnati graph response (8 MiB).
+static DEFAULT_MAX_GRAPH_BYTES: u64 = 8 * 1024 * 1024;
+
+/// Default directory for trusted graph signing keys.
+static DEFAULT_KEYRING_DIR: &str = "/etc/zincati/graph-keys.d";
+
+/// Default time without progress before cancelling an rpm-ostree transaction.
+static DEFAULT_STALL_TIMEOUT_SECS: u64 = 600;
+
+/// Default version of the `mock` backend booted deployment.
+static DEFAULT_MOCK_VERSION: &str = "0.0.0-mock";
+
+/// Default checksum of the `mock` backend booted deployment.
+static DEFAULT_MOCK_CHECKSUM: &str =
+    "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Default origin of the `mock` backend booted deployment.
+static DEFAULT_MOCK_ORIGIN: &str = "mock:fedora/x86_64/coreos/stable";
+
+/// Default directory for executable health checks.
+static DEFAULT_HEALTH_CHECKS_DIR: &str = "/etc/zincati/health.d";
+
+/// Default time for health checks to pass after reboot.
+static DEFAULT_HEALTH_GRACE_PERIOD_SECS: u64 = 300;
+
+/// Default timeout for a single health check.
+static DEFAULT_HEALTH_CHECK_TIMEOUT_SECS: u64 = 30;
+
+/// Default path of the control API socket.
+static DEFAULT_CONTROL_SOCKET: &str = "/run/zincati/control.sock";
+
+/// Default permissions of the control API socket.
+static DEFAULT_CONTROL_SOCKET_MODE: u32 = 0o600;
+
+/// Environment variable overriding the rpm-ostree DBus address.
+static RPMOSTREE_BUS_ADDRESS_ENV: &str = "ZINCATI_RPMOSTREE_BUS_ADDRESS";
+
 /// Runtime configuration for the agent.
 ///
 /// It holds validated agent configuration.
 #[derive(Debug, Serialize)]
 pub(crate) struct AgentConfig {
     pub(crate) identity: Identity,
-    #[serde(with = "url_serde")]
-    pub(crate) cincinnati: reqwest::Url,
+    pub(crate) scheduler: Scheduler,
+    pub(crate) state_file: StateFile,
+    pub(crate) blocklist: Blocklist,
+    /// Prometheus textfile to keep up to date, if any.
+    pub(crate) metrics_textfile: Option<std::path::PathBuf>,
+    pub(crate) cincinnati: CincinnatiConfig,
+    pub(crate) rpm_ostree: RpmOstreeConfig,
+    pub(crate) health: HealthConfig,
+    pub(crate) control: ControlConfig,
     pub(crate) strategy: strategy::UpStrategy,
 }
 
+/// Validated configuration for the Cincinnati client.
+#[derive(Debug, Serialize)]
+pub(crate) struct CincinnatiConfig {
+    #[serde(with = "url_serde")]
+    pub(crate) base_url: url::Url,
+    pub(crate) tls: TlsConfig,
+    pub(crate) client: ClientConfig,
+    /// Maximum size of a graph response, in bytes.
+    pub(crate) max_graph_bytes: u64,
+    /// Trusted keys for graph signatures, if verification is enabled.
+    pub(crate) keyring: Option<Keyring>,
+}
+
+/// Validated configuration for the rpm-ostree client.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct RpmOstreeConfig {
+    pub(crate) backend: RpmOstreeBackend,
+    /// Custom DBus address, instead of the system bus.
+    pub(crate) bus_address: Option<String>,
+    /// Maximum time without progress for a transaction.
+    pub(crate) stall_timeout: std::time::Duration,
+    pub(crate) mock: MockConfig,
+}
+
+/// OS-update backend.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub(crate) enum RpmOstreeBackend {
+    /// rpm-ostree over DBus.
+    Dbus,
+    /// rpm-ostree command-line interface.
+    Cli,
+    /// In-memory simulated system.
+    Mock,
+}
+
+/// Validated configuration for the `mock` backend.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct MockConfig {
+    pub(crate) booted_version: String,
+    pub(crate) booted_checksum: String,
+    pub(crate) booted_origin: String,
+    pub(crate) fail: Vec<String>,
+}
+
+impl MockConfig {
+    fn from_input(cfg: inputs::MockInput) -> Self {
+        let or_default = |value: String, default: &str| {
+            if value.is_empty() {
+                default.to_string()
+            } else {
+                value
+            }
+        };
+        Self {
+            booted_version: or_default(cfg.booted_version, DEFAULT_MOCK_VERSION),
+            booted_checksum: or_default(cfg.booted_checksum, DEFAULT_MOCK_CHECKSUM),
+            booted_origin: or_default(cfg.booted_origin, DEFAULT_MOCK_ORIGIN),
+            fail: cfg.fail,
+        }
+    }
+}
+
+/// Validated configuration for post-reboot health checks.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct HealthConfig {
+    /// Directory with executable checks.
+    pub(crate) checks_dir: std::path::PathBuf,
+    /// systemd units which must be active.
+    pub(crate) units: Vec<String>,
+    /// Maximum number of failed systemd units, if checked.
+    pub(crate) max_failed_units: Option<u32>,
+    /// Local HTTP endpoints which must be reachable.
+    pub(crate) http_endpoints: Vec<String>,
+    /// Time for checks to pass after reboot.
+    pub(crate) grace_period: std::time::Duration,
+    /// Timeout for a single check.
+    pub(crate) check_timeout: std::time::Duration,
+}
+
+impl HealthConfig {
+    fn try_from_input(cfg: inputs::HealthInput) -> Fallible<Self> {
+        // Endpoints are probed without proxy nor pins, so only
+        // allow local ones.
+        for endpoint in &cfg.http_endpoints {
+            let url = url::Url::parse(endpoint)
+                .context(format!("invalid health endpoint '{}'", endpoint))?;
+            let host = url.host_str().unwrap_or_default();
+            let local = host == "localhost"
+                || host
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .parse::<std::net::IpAddr>()
+                    .map(|ip| ip.is_loopback())
+                    .unwrap_or(false);
+            if !local {
+                bail!("health endpoint '{}' is not on localhost", endpoint);
+            }
+        }
+
+        let checks_dir = if cfg.checks_dir.is_empty() {
+            DEFAULT_HEALTH_CHECKS_DIR.into()
+        } else {
+            cfg.checks_dir.into()
+        };
+        let health = Self {
+            checks_dir,
+            units: cfg.units,
+            max_failed_units: cfg.max_failed_units,
+            http_endpoints: cfg.http_endpoints,
+            grace_period: std::time::Duration::from_secs(
+                cfg.grace_period_secs
+                    .unwrap_or(DEFAULT_HEALTH_GRACE_PERIOD_SECS),
+            ),
+            check_timeout: std::time::Duration::from_secs(
+                cfg.check_timeout_secs
+                    .unwrap_or(DEFAULT_HEALTH_CHECK_TIMEOUT_SECS),
+            ),
+        };
+        Ok(health)
+    }
+}
+
+/// Validated configuration for the local control API.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct ControlConfig {
+    /// Unix socket path, if the API is enabled.
+    pub(crate) socket_path: Option<std::path::PathBuf>,
+    /// Socket permissions.
+    pub(crate) socket_mode: u32,
+    /// Whether to serve the DBus interface.
+    pub(crate) dbus_service: bool,
+}
+
+impl ControlConfig {
+    /// Read only the control API configuration.
+    ///
+    /// This is meant for command-line clients, which may lack access to
+    /// files referenced by the full configuration (e.g. TLS client keys).
+    pub(crate) fn read_config(dirs: Vec<&str>) -> Fallible<Self> {
+        let cfg = inputs::ConfigInput::read_config(dirs)?;
+        let control = Self::try_from_input(cfg.control).context("invalid control configuration")?;
+        Ok(control)
+    }
+
+    fn try_from_input(cfg: inputs::ControlInput) -> Fallible<Self> {
+        let socket_mode = if cfg.socket_mode.is_empty() {
+            DEFAULT_CONTROL_SOCKET_MODE
+        } else {
+            u32::from_str_radix(cfg.socket_mode.trim_start_matches("0o"), 8)
+                .context(format!("invalid socket mode '{}'", cfg.socket_mode))?
+        };
+        if socket_mode & !0o777 != 0 {
+            bail!("invalid socket mode '{}'", cfg.socket_mode);
+        }
+
+        let socket_path = match (cfg.enabled, cfg.socket_path.is_empty()) {
+            (Some(false), _) => None,
+            (_, true) => Some(DEFAULT_CONTROL_SOCKET.into()),
+            (_, false) => Some(cfg.socket_path.into()),
+        };
+        let control = Self {
+            socket_path,
+            socket_mode,
+            dbus_service: cfg.dbus_service,
+        };
+        Ok(control)
+    }
+}
+
+/// Read only the blocklist location, see `ControlConfig::read_config`.
+pub(crate) fn read_blocklist_config(dirs: Vec<&str>) -> Fallible<Blocklist> {
+    let cfg = inputs::ConfigInput::read_config(dirs)?;
+    Ok(Blocklist::from_config(&cfg.agent))
+}
+
 impl AgentConfig {
     pub(crate) fn read_config(_dirs: Vec<&str>) -> Fallible<Self> {
         let cfg = inputs::ConfigInput::read_config(_dirs)?;
@@ -32,18 +255,89 @@ impl AgentConfig {
 
     /// Validate inputs and return a valid agent configuration.
     fn try_from_input(cfg: inputs::ConfigInput) -> Fallible<Self> {
-        let cincinnati = if !cfg.cincinnati.base_url.is_empty() {
-            reqwest::Url::parse(&cfg.cincinnati.base_url)?
+        let scheduler =
+            Scheduler::try_from_config(&cfg.agent).context("invalid agent configuration")?;
+        let state_file = StateFile::from_config(&cfg.agent);
+        let blocklist = Blocklist::from_config(&cfg.agent);
+        let metrics_textfile = if cfg.agent.metrics_textfile.is_empty() {
+            None
         } else {
-            reqwest::Url::parse("http://localhost:9876")?
+            Some(cfg.agent.metrics_textfile.clone().into())
+        };
+        let base_url = if !cfg.cincinnati.base_url.is_empty() {
+            url::Url::parse(&cfg.cincinnati.base_url)?
+        } else {
+            url::Url::parse("http://localhost:9876")?
+        };
+        let tls = TlsConfig::try_from_input(cfg.cincinnati.tls, base_url.scheme() == "https")
+            .context("invalid Cincinnati TLS configuration")?;
+        let client = ClientConfig::try_from_input(cfg.cincinnati.client)
+            .context("invalid Cincinnati HTTP configuration")?;
+        let keyring = if cfg.cincinnati.verify_signatures {
+            let dir = if cfg.cincinnati.keyring_dir.is_empty() {
+                DEFAULT_KEYRING_DIR
+            } else {
+                &cfg.cincinnati.keyring_dir
+            };
+            let keys = Keyring::load(std::path::Path::new(dir))
+                .context("failed to load graph signing keys")?;
+            Some(keys)
+        } else {
+            None
+        };
+        let cincinnati = CincinnatiConfig {
+            base_url,
+            tls,
+            client,
+            max_graph_bytes: cfg
+                .cincinnati
+                .max_graph_bytes
+                .unwrap_or(DEFAULT_MAX_GRAPH_BYTES),
+            keyring,
+        };
+        // Environment takes precedence over config, to ease pointing
+        // the agent to a private bus.
+        let bus_address = std::env::var(RPMOSTREE_BUS_ADDRESS_ENV)
+            .ok()
+            .unwrap_or(cfg.rpm_ostree.bus_address);
+        let backend = match cfg.rpm_ostree.backend.as_ref() {
+            "dbus" | "" => RpmOstreeBackend::Dbus,
+            "cli" => RpmOstreeBackend::Cli,
+            "mock" => RpmOstreeBackend::Mock,
+            x => bail!("unsupported rpm-ostree backend '{}'", x),
+        };
+        let rpm_ostree = RpmOstreeConfig {
+            backend,
+            bus_address: if bus_address.is_empty() {
+                None
+            } else {
+                Some(bus_address)
+            },
+            stall_timeout: std::time::Duration::from_secs(
+                cfg.rpm_ostree
+                    .stall_timeout_secs
+                    .unwrap_or(DEFAULT_STALL_TIMEOUT_SECS),
+            ),
+            mock: MockConfig::from_input(cfg.rpm_ostree.mock),
         };
+        let health =
+            HealthConfig::try_from_input(cfg.health).context("invalid health configuration")?;
+        let control =
+            ControlConfig::try_from_input(cfg.control).context("invalid control configuration")?;
         let identity = Identity::try_from_config(cfg.identity)
             .context("failed to build identity")?;
         let strategy = strategy::UpStrategy::try_from_config(cfg.updates)?;
 
         let state = AgentConfig {
+            blocklist,
             cincinnati,
+            control,
+            health,
             identity,
+            metrics_textfile,
+            rpm_ostree,
+            scheduler,
+            state_file,
             strategy,
         };
         debug!(
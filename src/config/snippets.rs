@@ -9,11 +9,33 @@ pub(crate) struct ConfigSnippet {
     pub(crate) updates: Option<UpdateSnippet>,
     /// Agent identity.
     pub(crate) identity: Option<IdentitySnippet>,
+    /// rpm-ostree client configuration.
+    pub(crate) rpm_ostree: Option<RpmOstreeSnippet>,
+    /// Post-reboot health checks configuration.
+    pub(crate) health: Option<HealthSnippet>,
+    /// Local control API configuration.
+    pub(crate) control: Option<ControlSnippet>,
 }
 
 /// General agent configuration.
 #[derive(Debug, Deserialize)]
 pub(crate) struct AgentSnippet {
+    /// Refresh period, in seconds (default: 300).
+    pub(crate) refresh_period_secs: Option<u64>,
+    /// Maximum random delay added to each refresh, in seconds (default: half the period).
+    pub(crate) splay_secs: Option<u64>,
+    /// Cap for exponential backoff on errors, in seconds (default: 3600).
+    pub(crate) max_backoff_secs: Option<u64>,
+    /// Staging attempts for a release before abandoning it (default: 3).
+    pub(crate) max_staging_attempts: Option<u32>,
+    /// Finalization attempts for a release before abandoning it (default: 3).
+    pub(crate) max_finalization_attempts: Option<u32>,
+    /// Path to the persistent state file (default: /var/lib/zincati/state.json).
+    pub(crate) state_file: Option<String>,
+    /// Path to the bad-release blocklist (default: /var/lib/zincati/blocklist.json).
+    pub(crate) blocklist_file: Option<String>,
+    /// Prometheus textfile, updated on every refresh (default: none).
+    pub(crate) metrics_textfile: Option<String>,
 //    /// Whether to print input configuration, for debug.
 //    pub(crate) debug_input_config: Option<bool>,
 //    /// Whether to print validated runtime configuration, for debug.
@@ -25,6 +47,8 @@ pub(crate) struct IdentitySnippet {
     /// Update group for this agent (default: 'default')
     pub(crate) group: Option<String>,
     pub(crate) node_uuid: Option<String>,
+    /// Update stream to follow (default: the booted one)
+    pub(crate) stream: Option<String>,
     /// Throttle bucket for this agent (default: dynamically computed)
     pub(crate) throttle_permille: Option<String>,
 }
@@ -35,6 +59,74 @@ pub(crate) struct IdentitySnippet {
 pub(crate) struct CincinnatiSnippet {
     /// Base URL to upstream cincinnati server.
     pub(crate) base_url: Option<String>,
+    /// TLS settings for the Cincinnati client.
+    pub(crate) tls: Option<TlsSnippet>,
+    /// HTTP settings for the Cincinnati client.
+    pub(crate) client: Option<ClientSnippet>,
+    /// Maximum size of a graph response, in bytes (default: 8 MiB).
+    pub(crate) max_graph_bytes: Option<u64>,
+    /// Whether to require signed graphs (default: false).
+    pub(crate) verify_signatures: Option<bool>,
+    /// Directory with trusted graph signing keys (default: /etc/zincati/graph-keys.d).
+    pub(crate) keyring_dir: Option<String>,
+}
+
+/// Config snippet for rpm-ostree client.
+#[derive(Debug, Deserialize)]
+pub(crate) struct RpmOstreeSnippet {
+    /// Backend for OS updates: dbus / cli / mock (default: dbus).
+    pub(crate) backend: Option<String>,
+    /// Custom DBus address for rpm-ostree (default: system bus).
+    ///
+    /// Overridden by `ZINCATI_RPMOSTREE_BUS_ADDRESS` in the environment.
+    pub(crate) bus_address: Option<String>,
+    /// Time without progress before cancelling a transaction, in seconds (default: 600).
+    pub(crate) stall_timeout_secs: Option<u64>,
+    /// `mock` backend config.
+    pub(crate) mock: Option<MockSnippet>,
+}
+
+/// Config snippet for the `mock` rpm-ostree backend.
+#[derive(Debug, Deserialize)]
+pub(crate) struct MockSnippet {
+    /// Version of the simulated booted deployment.
+    pub(crate) booted_version: Option<String>,
+    /// Checksum of the simulated booted deployment.
+    pub(crate) booted_checksum: Option<String>,
+    /// Origin refspec of the simulated booted deployment.
+    pub(crate) booted_origin: Option<String>,
+    /// Operations which always fail (stage / finalize / status / cleanup / rollback).
+    pub(crate) fail: Option<Vec<String>>,
+}
+
+/// Config snippet for post-reboot health checks.
+#[derive(Debug, Deserialize)]
+pub(crate) struct HealthSnippet {
+    /// Directory with executable checks (default: /etc/zincati/health.d).
+    pub(crate) checks_dir: Option<String>,
+    /// systemd units which must be active.
+    pub(crate) units: Option<Vec<String>>,
+    /// Maximum number of failed systemd units (default: unchecked).
+    pub(crate) max_failed_units: Option<u32>,
+    /// Local HTTP endpoints which must be reachable.
+    pub(crate) http_endpoints: Option<Vec<String>>,
+    /// Time for checks to pass after reboot, in seconds (default: 300).
+    pub(crate) grace_period_secs: Option<u64>,
+    /// Timeout for a single check, in seconds (default: 30).
+    pub(crate) check_timeout_secs: Option<u64>,
+}
+
+/// Config snippet for the local control API.
+#[derive(Debug, Deserialize)]
+pub(crate) struct ControlSnippet {
+    /// Whether to serve the control API (default: true).
+    pub(crate) enabled: Option<bool>,
+    /// Path to the Unix socket (default: /run/zincati/control.sock).
+    pub(crate) socket_path: Option<String>,
+    /// Socket permissions, in octal (default: 0600).
+    pub(crate) socket_mode: Option<String>,
+    /// Whether to own `org.coreos.zincati1` on the system bus (default: false).
+    pub(crate) dbus_service: Option<bool>,
 }
 
 /// Config snippet for update logic.
@@ -44,7 +136,8 @@ pub(crate) struct UpdateSnippet {
     pub(crate) strategy: Option<String>,
     /// `remote_http` strategy config.
     pub(crate) remote_http: Option<StratHttpSnippet>,
-    /// `periodic` strategy config.
+    /// `periodic` strategy config (no options yet).
+    #[allow(dead_code)]
     pub(crate) periodic: Option<StratPeriodicSnippet>,
 }
 
@@ -53,6 +146,40 @@ pub(crate) struct UpdateSnippet {
 pub(crate) struct StratHttpSnippet {
     /// Base URL for the remote semaphore manager.
     pub(crate) base_url: Option<String>,
+    /// TLS settings for the lock-manager client.
+    pub(crate) tls: Option<TlsSnippet>,
+    /// HTTP settings for the lock-manager client.
+    pub(crate) client: Option<ClientSnippet>,
+}
+
+/// Config snippet for TLS settings of an HTTP client.
+#[derive(Debug, Deserialize)]
+pub(crate) struct TlsSnippet {
+    /// Additional CA bundles (PEM) to trust.
+    pub(crate) ca_bundles: Option<Vec<String>>,
+    /// Whether to trust the system CA bundle (default: true).
+    pub(crate) system_ca_bundle: Option<bool>,
+    /// Client certificate chain (PEM), for mutual TLS.
+    pub(crate) client_cert: Option<String>,
+    /// Client private key (PEM), for mutual TLS.
+    pub(crate) client_key: Option<String>,
+    /// Minimum TLS version, "1.2" (default) or "1.3".
+    pub(crate) min_tls_version: Option<String>,
+    /// Pinned server public keys, as "sha256/<base64 SPKI digest>".
+    pub(crate) spki_pins: Option<Vec<String>>,
+}
+
+/// Config snippet for HTTP settings of a remote service client.
+#[derive(Debug, Deserialize)]
+pub(crate) struct ClientSnippet {
+    /// Proxy URL for HTTP and HTTPS requests.
+    pub(crate) proxy: Option<String>,
+    /// Hosts (and their subdomains) which bypass the proxy.
+    pub(crate) no_proxy: Option<Vec<String>>,
+    /// Timeout for establishing a connection, in seconds (default: 30).
+    pub(crate) connect_timeout_secs: Option<u64>,
+    /// Timeout for a whole request, in seconds (default: 120).
+    pub(crate) request_timeout_secs: Option<u64>,
 }
 
 /// Config snippet for `periodic` update strategy.
@@ -0,0 +1,157 @@
+//! Command-line client for the running agent.
+
+use super::client::ControlClient;
+use crate::config::ControlConfig;
+use failure::Fallible;
+use serde_json::Value;
+use std::time;
+
+/// Requests to the running agent, from the command-line.
+#[derive(Debug)]
+pub(crate) enum ClientCommand {
+    Status { json: bool },
+    Check,
+    Pause,
+    Resume,
+    FinalizeNow,
+    ClearAbandoned,
+}
+
+/// Run a client command against the running agent.
+pub(crate) fn run_client_cli(cfg: &ControlConfig, cmd: ClientCommand) -> Fallible<()> {
+    let client = ControlClient::try_from_config(cfg)?;
+    match cmd {
+        ClientCommand::Status { json } => {
+            let status = client.get("status")?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&status)?);
+            } else {
+                print_status(&status);
+            }
+        }
+        ClientCommand::Check => {
+            client.post("check")?;
+            println!("Update check requested");
+        }
+        ClientCommand::Pause => {
+            client.post("pause")?;
+            println!("Updates paused");
+        }
+        ClientCommand::Resume => {
+            client.post("resume")?;
+            println!("Updates resumed");
+        }
+        ClientCommand::FinalizeNow => {
+            client.post("finalize")?;
+            println!("Finalization requested, the node will reboot");
+        }
+        ClientCommand::ClearAbandoned => {
+            client.post("clear-abandoned")?;
+            println!("Abandoned update cleared");
+        }
+    }
+    Ok(())
+}
+
+/// Print agent status in human-readable form.
+fn print_status(status: &Value) {
+    let field = |name: &str| status.get(name).filter(|v| !v.is_null());
+
+    println!(
+        "State: {}",
+        field("state").and_then(Value::as_str).unwrap_or("unknown")
+    );
+    if let Some(booted) = field("booted_version").and_then(Value::as_str) {
+        println!("Booted: {}", booted);
+    }
+    if let Some(target) = field("target_version").and_then(Value::as_str) {
+        println!("Target: {}", target);
+    }
+    if let Some(at) = field("last_check_at").and_then(Value::as_u64) {
+        println!("Last check: {}", format_age(at));
+    }
+    if field("paused").and_then(Value::as_bool).unwrap_or(false) {
+        println!("Paused: yes");
+    }
+    if let Some(op) = field("in_flight") {
+        println!(
+            "In progress: {} (for {}s)",
+            op["operation"].as_str().unwrap_or("unknown"),
+            op["elapsed_secs"].as_u64().unwrap_or(0)
+        );
+    }
+    if let Some(secs) = field("reboot_pending_secs").and_then(Value::as_u64) {
+        println!("Reboot pending for: {}s", secs);
+    }
+    if let Some(retry) = field("retry") {
+        println!(
+            "Failed attempts: {}/{}",
+            retry["attempts"].as_u64().unwrap_or(0),
+            retry["max_attempts"].as_u64().unwrap_or(0)
+        );
+    }
+    if let Some(reason) = field("abandon_reason").and_then(Value::as_str) {
+        println!("Abandoned: {}", reason);
+    }
+    if let Some(last) = field("last_update") {
+        let outcome = if last["success"].as_bool().unwrap_or(false) {
+            "succeeded"
+        } else {
+            "failed"
+        };
+        println!(
+            "Last update: to '{}' {}, booted '{}'",
+            last["target_version"].as_str().unwrap_or("unknown"),
+            outcome,
+            last["booted_version"].as_str().unwrap_or("unknown")
+        );
+    }
+}
+
+/// Format a Unix timestamp as its age, e.g. "3m 12s ago".
+fn format_age(timestamp: u64) -> String {
+    let now = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    if timestamp > now {
+        return "just now".to_string();
+    }
+    format!("{} ago", format_duration(now - timestamp))
+}
+
+/// Format a duration in seconds, with its two most significant units.
+fn format_duration(secs: u64) -> String {
+    let units = [(86_400, "d"), (3600, "h"), (60, "m"), (1, "s")];
+    let parts: Vec<String> = units
+        .iter()
+        .scan(secs, |rest, (size, unit)| {
+            let count = *rest / size;
+            *rest %= size;
+            Some((count, unit))
+        })
+        .skip_while(|(count, _)| *count == 0)
+        .take(2)
+        .filter(|(count, _)| *count > 0)
+        .map(|(count, unit)| format!("{}{}", count, unit))
+        .collect();
+    if parts.is_empty() {
+        "0s".to_string()
+    } else {
+        parts.join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(0), "0s");
+        assert_eq!(format_duration(42), "42s");
+        assert_eq!(format_duration(192), "3m 12s");
+        assert_eq!(format_duration(3600), "1h");
+        assert_eq!(format_duration(3 * 86_400 + 7200 + 59), "3d 2h");
+    }
+}
@@ -0,0 +1,90 @@
+//! Minimal client for the control API.
+//!
+//! Requests are plain HTTP/1.0 over the Unix socket, one connection per
+//! request, so that no async runtime is needed on the client side.
+
+use crate::config::ControlConfig;
+use failure::{Fallible, ResultExt};
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time;
+
+/// Timeout for a single request.
+static REQUEST_TIMEOUT_SECS: u64 = 30;
+
+/// Control API client.
+#[derive(Clone, Debug)]
+pub(crate) struct ControlClient {
+    path: PathBuf,
+}
+
+impl ControlClient {
+    /// Validate inputs and return a client for the configured socket.
+    pub(crate) fn try_from_config(cfg: &ControlConfig) -> Fallible<Self> {
+        match cfg.socket_path {
+            Some(ref path) => Ok(Self { path: path.clone() }),
+            None => bail!("control API is disabled"),
+        }
+    }
+
+    /// Send a GET request, returning the JSON reply.
+    pub(crate) fn get(&self, endpoint: &str) -> Fallible<serde_json::Value> {
+        self.request("GET", endpoint)
+    }
+
+    /// Send a POST request, returning the JSON reply.
+    pub(crate) fn post(&self, endpoint: &str) -> Fallible<serde_json::Value> {
+        self.request("POST", endpoint)
+    }
+
+    fn request(&self, method: &str, endpoint: &str) -> Fallible<serde_json::Value> {
+        let mut stream = UnixStream::connect(&self.path).context(format!(
+            "failed to connect to '{}', is the agent running?",
+            self.path.display()
+        ))?;
+        let timeout = Some(time::Duration::from_secs(REQUEST_TIMEOUT_SECS));
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+
+        let req = format!(
+            "{} /v1/{} HTTP/1.0\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n",
+            method, endpoint
+        );
+        stream.write_all(req.as_bytes())?;
+        let mut resp = Vec::new();
+        stream
+            .read_to_end(&mut resp)
+            .context("failed to read reply")?;
+
+        let (status, body) = parse_response(&resp)?;
+        let value: serde_json::Value = if body.is_empty() {
+            serde_json::Value::Null
+        } else {
+            serde_json::from_slice(body).context("failed to parse reply")?
+        };
+        if status / 100 != 2 {
+            match value.get("error").and_then(|e| e.as_str()) {
+                Some(e) => bail!("{}", e),
+                None => bail!("request failed with status {}", status),
+            }
+        }
+        Ok(value)
+    }
+}
+
+/// Split an HTTP response into status code and body.
+fn parse_response(resp: &[u8]) -> Fallible<(u16, &[u8])> {
+    let split = resp
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| format_err!("malformed reply"))?;
+    let head = String::from_utf8_lossy(&resp[..split]);
+    let status = head
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| format_err!("malformed reply status"))?;
+    Ok((status, &resp[split + 4..]))
+}
@@ -0,0 +1,368 @@
+//! DBus interface for the update agent.
+//!
+//! The agent owns `org.coreos.zincati1` on the system bus, exporting its
+//! status as properties and operator requests as methods. Properties are
+//! a snapshot of the agent status, published by the agent on changes, and
+//! changes are announced via `PropertiesChanged`.
+//!
+//! DBus calls are blocking, so the service runs on its own thread and
+//! waits on the agent for method replies, driving requests on a runtime
+//! of its own.
+//!
+//! Access is enforced by the bus policy shipped in
+//! `dist/dbus-1/system.d/org.coreos.zincati1.conf`: only the zincati user
+//! can own the name, and methods are restricted to root and the zincati
+//! group, while properties stay readable by everyone.
+
+use crate::update_agent::{self, AgentStatus, StatusWatch, UpdateAgent};
+use actix::dev::ToEnvelope;
+use actix::prelude::*;
+use dbus::arg::{RefArg, Variant};
+use dbus::tree::{Factory, MTFn, MethodErr, MethodInfo};
+use dbus::{BusType, Connection, NameFlag};
+use failure::{Error, Fallible, ResultExt};
+use futures::{future, Future};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+use tokio::runtime::current_thread::Runtime;
+
+/// Well-known bus name.
+static BUS_NAME: &str = "org.coreos.zincati1";
+
+/// Object path of the agent.
+static OBJECT_PATH: &str = "/org/coreos/zincati1";
+
+/// Interface of the agent.
+static AGENT_INTERFACE: &str = "org.coreos.zincati1.Agent";
+
+/// Standard DBus properties interface.
+static PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+
+/// Timeout for incoming messages, in milliseconds.
+///
+/// This also bounds the delay for announcing status changes.
+static POLL_MS: u32 = 500;
+
+/// Timeout for agent replies to method calls, in seconds.
+///
+/// The service thread is blocked while waiting, so this keeps a stuck
+/// agent from stalling the bus connection as well.
+static AGENT_TIMEOUT_SECS: u64 = 10;
+
+/// Delay before restarting the service after an error, in seconds.
+static RESTART_DELAY_SECS: u64 = 30;
+
+/// Snapshot of exported properties.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Properties {
+    state: String,
+    current_version: String,
+    target_version: String,
+    last_check_time: u64,
+    paused: bool,
+}
+
+impl From<AgentStatus> for Properties {
+    fn from(status: AgentStatus) -> Self {
+        Self {
+            state: status.state.to_string(),
+            current_version: status.booted_version.unwrap_or_default(),
+            target_version: status.target_version.unwrap_or_default(),
+            last_check_time: status.last_check_at.unwrap_or(0),
+            paused: status.paused,
+        }
+    }
+}
+
+impl Properties {
+    /// Properties which differ from a previous snapshot, as DBus values.
+    fn changed_since(&self, old: &Self) -> HashMap<&'static str, Variant<Box<dyn RefArg>>> {
+        let mut changed: HashMap<&'static str, Variant<Box<dyn RefArg>>> = HashMap::new();
+        if self.state != old.state {
+            changed.insert("State", Variant(Box::new(self.state.clone())));
+        }
+        if self.current_version != old.current_version {
+            let value = self.current_version.clone();
+            changed.insert("CurrentVersion", Variant(Box::new(value)));
+        }
+        if self.target_version != old.target_version {
+            let value = self.target_version.clone();
+            changed.insert("TargetVersion", Variant(Box::new(value)));
+        }
+        if self.last_check_time != old.last_check_time {
+            let value = self.last_check_time;
+            changed.insert("LastCheckTime", Variant(Box::new(value)));
+        }
+        if self.paused != old.paused {
+            changed.insert("Paused", Variant(Box::new(self.paused)));
+        }
+        changed
+    }
+}
+
+/// Start the DBus service, if enabled.
+pub(crate) fn start_service(
+    enabled: bool,
+    agent: Addr<UpdateAgent>,
+    watch: StatusWatch,
+) -> Fallible<()> {
+    if !enabled {
+        debug!("DBus service disabled");
+        return Ok(());
+    }
+
+    thread::Builder::new()
+        .name("dbus-service".to_string())
+        .spawn(move || {
+            // Properties outlive restarts, as changes are only published once.
+            let props = Rc::new(RefCell::new(Properties::default()));
+            loop {
+                if let Err(e) = serve(&agent, &watch, &props) {
+                    error!("DBus service failed: {}", e);
+                }
+                thread::sleep(Duration::from_secs(RESTART_DELAY_SECS));
+            }
+        })
+        .context("failed to start DBus service")?;
+    Ok(())
+}
+
+/// Own the bus name and serve requests, until an error occurs.
+fn serve(
+    agent: &Addr<UpdateAgent>,
+    watch: &StatusWatch,
+    props: &Rc<RefCell<Properties>>,
+) -> Fallible<()> {
+    let conn = Connection::get_private(BusType::System)
+        .map_err(|e| format_err!("failed to connect to DBus: {}", e))?;
+    conn.register_name(BUS_NAME, NameFlag::DoNotQueue as u32)
+        .map_err(|e| format_err!("failed to own '{}': {}", BUS_NAME, e))?;
+    let runtime = Rc::new(RefCell::new(
+        Runtime::new().context("failed to create DBus service runtime")?,
+    ));
+
+    let f = Factory::new_fn::<()>();
+    let method = |call: fn(&mut Runtime, &Addr<UpdateAgent>) -> Fallible<()>| {
+        let agent = agent.clone();
+        let runtime = runtime.clone();
+        move |m: &MethodInfo<MTFn<()>, ()>| {
+            call(&mut runtime.borrow_mut(), &agent).map_err(|e| MethodErr::failed(&e))?;
+            Ok(vec![m.msg.method_return()])
+        }
+    };
+
+    let (p1, p2, p3, p4, p5) = (
+        props.clone(),
+        props.clone(),
+        props.clone(),
+        props.clone(),
+        props.clone(),
+    );
+    let iface = f
+        .interface(AGENT_INTERFACE, ())
+        .add_p(f.property::<&str, _>("State", ()).on_get(move |i, _| {
+            i.append(p1.borrow().state.as_str());
+            Ok(())
+        }))
+        .add_p(
+            f.property::<&str, _>("CurrentVersion", ())
+                .on_get(move |i, _| {
+                    i.append(p2.borrow().current_version.as_str());
+                    Ok(())
+                }),
+        )
+        .add_p(
+            f.property::<&str, _>("TargetVersion", ())
+                .on_get(move |i, _| {
+                    i.append(p3.borrow().target_version.as_str());
+                    Ok(())
+                }),
+        )
+        .add_p(
+            f.property::<u64, _>("LastCheckTime", ())
+                .on_get(move |i, _| {
+                    i.append(p4.borrow().last_check_time);
+                    Ok(())
+                }),
+        )
+        .add_p(f.property::<bool, _>("Paused", ()).on_get(move |i, _| {
+            i.append(p5.borrow().paused);
+            Ok(())
+        }))
+        .add_m(f.method(
+            "Check",
+            (),
+            method(|rt, a| request(rt, a, update_agent::TriggerCheck {})),
+        ))
+        .add_m(f.method(
+            "Pause",
+            (),
+            method(|rt, a| request(rt, a, update_agent::SetPaused { paused: true })),
+        ))
+        .add_m(f.method(
+            "Resume",
+            (),
+            method(|rt, a| request(rt, a, update_agent::SetPaused { paused: false })),
+        ));
+    let tree = f
+        .tree(())
+        .add(f.object_path(OBJECT_PATH, ()).introspectable().add(iface));
+    tree.set_registered(&conn, true)
+        .map_err(|e| format_err!("failed to register DBus object: {}", e))?;
+    conn.add_handler(tree);
+    info!("DBus service '{}' started", BUS_NAME);
+
+    loop {
+        // Method calls are dispatched to the tree while draining messages.
+        for msg in conn.incoming(POLL_MS) {
+            trace!("ignoring unhandled DBus message: {:?}", msg);
+        }
+
+        let current = match watch.take() {
+            Some(status) => Properties::from(status),
+            None => continue,
+        };
+        let changed = current.changed_since(&props.borrow());
+        if changed.is_empty() {
+            continue;
+        }
+        *props.borrow_mut() = current;
+
+        let signal = dbus::Message::signal(
+            &OBJECT_PATH.into(),
+            &PROPERTIES_INTERFACE.into(),
+            &"PropertiesChanged".into(),
+        )
+        .append3(AGENT_INTERFACE, changed, Vec::<String>::new());
+        conn.send(signal)
+            .map_err(|_| format_err!("failed to emit PropertiesChanged"))?;
+    }
+}
+
+/// Send a request to the update agent, blocking until it replies or times out.
+///
+/// The service thread is outside of the actix system, so the request is
+/// driven on the given runtime, which also provides the reply timer.
+fn request<A, M, T>(runtime: &mut Runtime, agent: &Addr<A>, msg: M) -> Fallible<T>
+where
+    A: Handler<M>,
+    M: Message<Result = Result<T, Error>> + Send + 'static,
+    T: Send + 'static,
+    <A as Actor>::Context: ToEnvelope<A, M>,
+{
+    let agent = agent.clone();
+    let req = future::lazy(move || {
+        agent
+            .send(msg)
+            .timeout(Duration::from_secs(AGENT_TIMEOUT_SECS))
+    });
+    runtime
+        .block_on(req)
+        .map_err(|e| format_err!("failed to reach update agent: {}", e))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::release;
+    use crate::update_agent::UpdateAgentState;
+    use std::sync::mpsc;
+
+    fn props() -> Properties {
+        Properties {
+            state: UpdateAgentState::NoNewUpdate.label().to_string(),
+            current_version: "30.1".to_string(),
+            target_version: String::new(),
+            last_check_time: 1_580_000_000,
+            paused: false,
+        }
+    }
+
+    fn changed_names(new: &Properties, old: &Properties) -> Vec<&'static str> {
+        let mut names: Vec<_> = new.changed_since(old).keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_changed_since() {
+        let old = props();
+        assert!(old.changed_since(&old).is_empty());
+
+        let mut new = old.clone();
+        new.state = UpdateAgentState::UpdateStaged(release("30.2", 'b'))
+            .label()
+            .to_string();
+        new.target_version = "30.2".to_string();
+        new.last_check_time += 60;
+        assert_eq!(
+            changed_names(&new, &old),
+            vec!["LastCheckTime", "State", "TargetVersion"]
+        );
+        let changed = new.changed_since(&old);
+        assert_eq!(changed["State"].0.as_str(), Some("update-staged"));
+        assert_eq!(changed["TargetVersion"].0.as_str(), Some("30.2"));
+        assert_eq!(changed["LastCheckTime"].0.as_u64(), Some(1_580_000_060));
+
+        let mut new = old.clone();
+        new.current_version = "30.2".to_string();
+        new.paused = true;
+        assert_eq!(changed_names(&new, &old), vec!["CurrentVersion", "Paused"]);
+        let changed = new.changed_since(&old);
+        assert_eq!(changed["CurrentVersion"].0.as_str(), Some("30.2"));
+        assert_eq!(changed["Paused"].0.as_i64(), Some(1));
+
+        // Everything differs from the initial empty snapshot.
+        assert_eq!(changed_names(&new, &Properties::default()).len(), 5);
+    }
+
+    /// Actor answering pings, standing in for the update agent.
+    struct Ponger;
+
+    impl Actor for Ponger {
+        type Context = Context<Self>;
+    }
+
+    /// Ping, refused unless `true`.
+    struct Ping(bool);
+
+    impl Message for Ping {
+        type Result = Result<&'static str, Error>;
+    }
+
+    impl Handler<Ping> for Ponger {
+        type Result = Result<&'static str, Error>;
+
+        fn handle(&mut self, msg: Ping, _ctx: &mut Self::Context) -> Self::Result {
+            if !msg.0 {
+                bail!("ping refused");
+            }
+            Ok("pong")
+        }
+    }
+
+    #[test]
+    fn test_request() {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let sys = System::new("test-dbus");
+            tx.send(Ponger.start()).unwrap();
+            sys.run();
+        });
+        let addr = rx.recv().unwrap();
+
+        // Like the DBus service, send from a bare thread with no runtime around.
+        let replies = thread::spawn(move || {
+            let mut runtime = Runtime::new().unwrap();
+            let pong = request(&mut runtime, &addr, Ping(true)).unwrap();
+            let refused = request(&mut runtime, &addr, Ping(false)).unwrap_err();
+            (pong, refused.to_string())
+        })
+        .join()
+        .unwrap();
+        assert_eq!(replies, ("pong", "ping refused".to_string()));
+    }
+}
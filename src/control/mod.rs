@@ -0,0 +1,276 @@
+//! Local control API.
+//!
+//! This module serves a small JSON-over-HTTP API on a Unix socket, for
+//! on-host tooling to query and steer the running agent. Access control
+//! is left to socket permissions.
+//!
+//! Endpoints:
+//!  * `GET /v1/status` - agent status.
+//!  * `GET /v1/config` - identity and runtime configuration.
+//!  * `GET /v1/metrics` - metrics, in Prometheus text format.
+//!  * `POST /v1/check` - check for updates now.
+//!  * `POST /v1/pause`, `POST /v1/resume` - pause or resume updates.
+//!  * `POST /v1/finalize` - finalize the staged update, bypassing the strategy.
+//!  * `POST /v1/clear-abandoned` - clear an abandoned update.
+//!
+//! It also provides the matching command-line client, and an optional
+//! DBus interface for tools which speak DBus natively.
+
+mod cli;
+mod client;
+mod dbus;
+
+pub(crate) use self::dbus::start_service as start_dbus_service;
+pub(crate) use cli::{run_client_cli, ClientCommand};
+
+use crate::config::ControlConfig;
+use crate::metrics;
+use crate::update_agent::{self, UpdateAgent};
+use actix::dev::ToEnvelope;
+use actix::prelude::*;
+use actix_web::{http, server, App, FutureResponse, HttpResponse, State};
+use failure::{Error, Fallible, ResultExt};
+use futures::prelude::*;
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Shared state for API handlers.
+struct ControlState {
+    /// Runtime configuration, as loaded on start.
+    ///
+    /// Secrets (proxy passwords, client key locations) are redacted when
+    /// serializing the configuration, thus never served.
+    config: serde_json::Value,
+}
+
+/// Error reply body.
+#[derive(Debug, Serialize)]
+struct ErrorReply {
+    error: String,
+}
+
+/// Start serving the control API, if enabled.
+///
+/// This must be called from within a running actix system, after the
+/// update agent has been registered.
+pub(crate) fn start(cfg: ControlConfig, config: serde_json::Value) -> Fallible<()> {
+    let path = match cfg.socket_path {
+        Some(p) => p,
+        None => {
+            debug!("control API disabled");
+            return Ok(());
+        }
+    };
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).context(format!("failed to create '{}'", dir.display()))?;
+    }
+    // Remove any stale socket from a previous run.
+    match fs::remove_file(&path) {
+        Ok(_) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        res => res.context(format!("failed to remove '{}'", path.display()))?,
+    };
+    let listener = bind_socket(&path, cfg.socket_mode)?;
+
+    let state = Arc::new(ControlState { config });
+    let server = server::new(move || app(state.clone())).workers(1);
+    // actix-web 0.7 has no other way to serve a Unix socket.
+    #[allow(deprecated)]
+    server.start_incoming(listener.incoming(), false);
+    info!("control API listening on '{}'", path.display());
+
+    Ok(())
+}
+
+/// Build the API router.
+fn app(state: Arc<ControlState>) -> App<Arc<ControlState>> {
+    App::with_state(state)
+        .prefix("/v1")
+        .resource("/status", |r| r.method(http::Method::GET).with(get_status))
+        .resource("/config", |r| r.method(http::Method::GET).with(get_config))
+        .resource("/metrics", |r| {
+            r.method(http::Method::GET).with(get_metrics)
+        })
+        .resource("/check", |r| r.method(http::Method::POST).with(post_check))
+        .resource("/pause", |r| r.method(http::Method::POST).with(post_pause))
+        .resource("/resume", |r| {
+            r.method(http::Method::POST).with(post_resume)
+        })
+        .resource("/finalize", |r| {
+            r.method(http::Method::POST).with(post_finalize)
+        })
+        .resource("/clear-abandoned", |r| {
+            r.method(http::Method::POST).with(post_clear_abandoned)
+        })
+}
+
+/// Bind a Unix socket, with the given permissions from the start.
+///
+/// The socket is created in a private (0700) directory next to its final
+/// path, and only moved in place once its permissions are set, so that
+/// it is never reachable with looser ones.
+fn bind_socket(path: &Path, mode: u32) -> Fallible<tokio::net::UnixListener> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| format_err!("invalid socket path '{}'", path.display()))?;
+    let private_dir = dir.join(format!(
+        ".{}.{}",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&private_dir);
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private_dir)
+        .context(format!("failed to create '{}'", private_dir.display()))?;
+
+    let bind = || -> Fallible<tokio::net::UnixListener> {
+        let tmp_path = private_dir.join(file_name);
+        let listener = tokio::net::UnixListener::bind(&tmp_path)
+            .context(format!("failed to bind to '{}'", tmp_path.display()))?;
+        fs::set_permissions(&tmp_path, fs::Permissions::from_mode(mode)).context(format!(
+            "failed to set permissions on '{}'",
+            tmp_path.display()
+        ))?;
+        fs::rename(&tmp_path, path)
+            .context(format!("failed to move socket to '{}'", path.display()))?;
+        Ok(listener)
+    };
+    let listener = bind();
+    if let Err(e) = fs::remove_dir_all(&private_dir) {
+        warn!("failed to remove '{}': {}", private_dir.display(), e);
+    }
+    listener
+}
+
+fn get_status(_state: State<Arc<ControlState>>) -> FutureResponse<HttpResponse> {
+    send_to_agent(update_agent::GetStatus {})
+}
+
+fn get_config(state: State<Arc<ControlState>>) -> HttpResponse {
+    HttpResponse::Ok().json(&state.config)
+}
+
+fn get_metrics(_state: State<Arc<ControlState>>) -> FutureResponse<HttpResponse> {
+    let addr = System::current().registry().get::<UpdateAgent>();
+    let reply = addr.send(update_agent::GetStatus {}).then(|res| {
+        let resp = match res {
+            Ok(Ok(status)) => HttpResponse::Ok()
+                .content_type("text/plain; version=0.0.4")
+                .body(metrics::render(&status)),
+            Ok(Err(e)) => HttpResponse::InternalServerError().body(e.to_string()),
+            Err(e) => HttpResponse::ServiceUnavailable().body(e.to_string()),
+        };
+        Ok(resp)
+    });
+    Box::new(reply)
+}
+
+fn post_check(_state: State<Arc<ControlState>>) -> FutureResponse<HttpResponse> {
+    send_to_agent(update_agent::TriggerCheck {})
+}
+
+fn post_pause(_state: State<Arc<ControlState>>) -> FutureResponse<HttpResponse> {
+    send_to_agent(update_agent::SetPaused { paused: true })
+}
+
+fn post_resume(_state: State<Arc<ControlState>>) -> FutureResponse<HttpResponse> {
+    send_to_agent(update_agent::SetPaused { paused: false })
+}
+
+fn post_finalize(_state: State<Arc<ControlState>>) -> FutureResponse<HttpResponse> {
+    send_to_agent(update_agent::ForceFinalize {})
+}
+
+fn post_clear_abandoned(_state: State<Arc<ControlState>>) -> FutureResponse<HttpResponse> {
+    send_to_agent(update_agent::ClearAbandoned {})
+}
+
+/// Send a request to the update agent, and reply with its result as JSON.
+///
+/// Requests rejected by the agent result in a `409 Conflict`.
+fn send_to_agent<M, T>(msg: M) -> FutureResponse<HttpResponse>
+where
+    M: Message<Result = Result<T, Error>> + Send + 'static,
+    T: Serialize + Send + 'static,
+    UpdateAgent: Handler<M>,
+    <UpdateAgent as Actor>::Context: ToEnvelope<UpdateAgent, M>,
+{
+    let addr = System::current().registry().get::<UpdateAgent>();
+    let reply = addr.send(msg).then(|res| {
+        let resp = match res {
+            Ok(Ok(value)) => HttpResponse::Ok().json(value),
+            Ok(Err(e)) => HttpResponse::Conflict().json(ErrorReply {
+                error: e.to_string(),
+            }),
+            Err(e) => {
+                error!("failed to reach update agent: {}", e);
+                HttpResponse::ServiceUnavailable().json(ErrorReply {
+                    error: e.to_string(),
+                })
+            }
+        };
+        Ok(resp)
+    });
+    Box::new(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestServer;
+    use actix_web::HttpMessage;
+
+    fn test_server(config: serde_json::Value) -> TestServer {
+        let state = Arc::new(ControlState { config });
+        TestServer::with_factory(move || app(state.clone()))
+    }
+
+    #[test]
+    fn test_get_config() {
+        let config = serde_json::json!({
+            "control": { "socket_path": "/run/zincati/private/control.sock" },
+        });
+        let mut srv = test_server(config.clone());
+
+        let req = srv
+            .client(http::Method::GET, "/v1/config")
+            .finish()
+            .unwrap();
+        let resp = srv.execute(req.send()).unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let body = srv.execute(resp.body()).unwrap();
+        let served: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(served, config);
+    }
+
+    #[test]
+    fn test_routes() {
+        let mut srv = test_server(serde_json::Value::Null);
+
+        let req = srv
+            .client(http::Method::GET, "/v1/unknown")
+            .finish()
+            .unwrap();
+        let resp = srv.execute(req.send()).unwrap();
+        assert_eq!(resp.status(), http::StatusCode::NOT_FOUND);
+
+        // Read-only endpoints reject other methods, and the other way round.
+        let req = srv
+            .client(http::Method::POST, "/v1/config")
+            .finish()
+            .unwrap();
+        let resp = srv.execute(req.send()).unwrap();
+        assert_eq!(resp.status(), http::StatusCode::METHOD_NOT_ALLOWED);
+
+        let req = srv.client(http::Method::GET, "/v1/pause").finish().unwrap();
+        let resp = srv.execute(req.send()).unwrap();
+        assert_eq!(resp.status(), http::StatusCode::METHOD_NOT_ALLOWED);
+    }
+}
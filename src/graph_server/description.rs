@@ -0,0 +1,179 @@
+//! Static graph description.
+
+use super::GraphParams;
+use failure::{Fallible, ResultExt};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Description of a graph of releases.
+#[derive(Debug, Deserialize)]
+pub(crate) struct GraphDescription {
+    /// All known releases.
+    #[serde(default)]
+    pub(crate) releases: Vec<ReleaseDescription>,
+    /// Update edges between releases.
+    #[serde(default)]
+    pub(crate) edges: Vec<EdgeDescription>,
+}
+
+/// Description of a single release.
+#[derive(Debug, Deserialize)]
+pub(crate) struct ReleaseDescription {
+    pub(crate) version: String,
+    pub(crate) payload: String,
+    #[serde(default)]
+    pub(crate) metadata: HashMap<String, String>,
+    /// Streams this release belongs to (default: all).
+    #[serde(default)]
+    pub(crate) streams: Vec<String>,
+    /// Architectures this release is available for (default: all).
+    #[serde(default)]
+    pub(crate) arches: Vec<String>,
+    /// Rollout progress, 0 (nobody) to 1000 (everybody) (default: 1000).
+    pub(crate) rollout_permille: Option<u16>,
+}
+
+/// Description of an update edge, by release version.
+#[derive(Debug, Deserialize)]
+pub(crate) struct EdgeDescription {
+    pub(crate) from: String,
+    pub(crate) to: String,
+}
+
+impl GraphDescription {
+    /// Read a graph description from a TOML or JSON file.
+    pub(crate) fn read_file(path: &Path) -> Fallible<Self> {
+        let content = std::fs::read(path).context(format!("failed to read file {:?}", path))?;
+        let description = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => serde_json::from_slice(&content).context("failed to parse JSON")?,
+            _ => toml::from_slice(&content).context("failed to parse TOML")?,
+        };
+        Ok(description)
+    }
+
+    /// Build a Cincinnati graph, filtered by client parameters.
+    pub(crate) fn to_graph(&self, params: &GraphParams) -> Fallible<libcincinnati::Graph> {
+        let mut graph = libcincinnati::Graph::default();
+        let mut ids = HashMap::new();
+
+        for rel in self.releases.iter().filter(|r| r.is_visible(params)) {
+            if ids.contains_key(&rel.version) {
+                bail!("duplicate release '{}'", rel.version);
+            }
+            let release = libcincinnati::ConcreteRelease {
+                version: rel.version.clone(),
+                payload: rel.payload.clone(),
+                metadata: rel.metadata.clone(),
+            };
+            let id = graph.add_release(release)?;
+            ids.insert(rel.version.clone(), id);
+        }
+
+        for edge in &self.edges {
+            if !self.releases.iter().any(|r| r.version == edge.from) {
+                bail!("edge from unknown release '{}'", edge.from);
+            }
+            if !self.releases.iter().any(|r| r.version == edge.to) {
+                bail!("edge to unknown release '{}'", edge.to);
+            }
+            // Edges touching filtered-out releases are dropped.
+            if let (Some(from), Some(to)) = (ids.get(&edge.from), ids.get(&edge.to)) {
+                graph.add_edge(from, to)?;
+            }
+        }
+
+        Ok(graph)
+    }
+}
+
+impl ReleaseDescription {
+    /// Check whether this release should be served to a client.
+    fn is_visible(&self, params: &GraphParams) -> bool {
+        if let Some(ref stream) = params.stream {
+            if !self.streams.is_empty() && !self.streams.contains(stream) {
+                return false;
+            }
+        }
+        if let Some(ref arch) = params.arch {
+            if !self.arches.is_empty() && !self.arches.contains(arch) {
+                return false;
+            }
+        }
+        match (params.throttle_permille, self.rollout_permille) {
+            // Not rolled out to anybody yet.
+            (_, Some(0)) => false,
+            (Some(throttle), Some(rollout)) => u32::from(throttle) + u32::from(rollout) >= 1000,
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn release(version: &str, rollout_permille: Option<u16>) -> ReleaseDescription {
+        let release = testing::concrete_release(version, 'a');
+        ReleaseDescription {
+            version: release.version,
+            payload: release.payload,
+            metadata: release.metadata,
+            streams: vec![],
+            arches: vec![],
+            rollout_permille,
+        }
+    }
+
+    fn params(throttle_permille: Option<u16>) -> GraphParams {
+        GraphParams {
+            throttle_permille,
+            ..GraphParams::default()
+        }
+    }
+
+    #[test]
+    fn test_rollout_visibility() {
+        // Visibility per release rollout, for each client throttle.
+        let throttles = [None, Some(0), Some(999), Some(1000)];
+        let cases = vec![
+            (None, [true, true, true, true]),
+            (Some(0), [false, false, false, false]),
+            (Some(999), [true, false, true, true]),
+            (Some(1000), [true, true, true, true]),
+        ];
+
+        for (rollout, expected) in cases {
+            let rel = release("30.1", rollout);
+            for (throttle, visible) in throttles.iter().zip(&expected) {
+                assert_eq!(
+                    rel.is_visible(&params(*throttle)),
+                    *visible,
+                    "rollout {:?}, throttle {:?}",
+                    rollout,
+                    throttle
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_graph_rollout_filter() {
+        let description = GraphDescription {
+            releases: vec![release("30.1", None), release("30.2", Some(999))],
+            edges: vec![EdgeDescription {
+                from: "30.1".to_string(),
+                to: "30.2".to_string(),
+            }],
+        };
+
+        let graph = description.to_graph(&params(Some(0))).unwrap();
+        assert!(graph.find_by_version("30.1").is_some());
+        assert!(graph.find_by_version("30.2").is_none());
+
+        let graph = description.to_graph(&params(Some(1))).unwrap();
+        let current = graph.find_by_version("30.1").unwrap();
+        let next: Vec<_> = graph.next_releases(&current).map(|r| r.version()).collect();
+        assert_eq!(next, vec!["30.2"]);
+    }
+}
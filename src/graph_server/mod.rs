@@ -0,0 +1,91 @@
+//! Local Cincinnati graph server.
+//!
+//! This module implements a tiny Cincinnati server, serving `/v1/graph`
+//! out of a static description of releases and update edges. It is meant
+//! for testing the agent without network access and for small sites
+//! which do not need a full Cincinnati stack.
+//!
+//! Releases can be restricted to specific streams and architectures, and
+//! gradually rolled out via `rollout_permille`: a release with rollout R
+//! is served to the most eager R permille of clients, i.e. to clients
+//! with `throttle_permille >= 1000 - R`.
+
+mod description;
+
+use actix_web::{http, server, App, HttpResponse, Query, State};
+use description::GraphDescription;
+use failure::{Fallible, ResultExt};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Command-line options for `graph-server`.
+#[derive(Debug, StructOpt)]
+pub(crate) struct CliOptions {
+    /// Graph description (TOML or JSON, by file extension)
+    #[structopt(parse(from_os_str))]
+    description: PathBuf,
+    /// Listening address (default matches the agent default Cincinnati URL)
+    #[structopt(long = "bind", default_value = "127.0.0.1:9876")]
+    bind: String,
+}
+
+/// Run a graph server until interrupted.
+pub(crate) fn run(opts: CliOptions) -> Fallible<()> {
+    let description = GraphDescription::read_file(&opts.description)?;
+    // Fail early on invalid descriptions.
+    description.to_graph(&GraphParams::default())?;
+    info!(
+        "serving graph with {} release(s) and {} edge(s)",
+        description.releases.len(),
+        description.edges.len()
+    );
+
+    let bind = opts.bind;
+    let state = Arc::new(description);
+
+    let sys = actix::System::new("zincati-graph-server");
+    server::new(move || {
+        App::with_state(state.clone()).resource("/v1/graph", |r| {
+            r.method(http::Method::GET).with(serve_graph)
+        })
+    })
+    .bind(&bind)
+    .context(format!("failed to bind to '{}'", bind))?
+    .start();
+    info!("graph server listening on {}", bind);
+
+    sys.run();
+    Ok(())
+}
+
+/// Client parameters for graph requests, all optional.
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct GraphParams {
+    pub(crate) stream: Option<String>,
+    pub(crate) arch: Option<String>,
+    pub(crate) throttle_permille: Option<u16>,
+}
+
+/// Serve a graph, filtered according to client parameters.
+fn serve_graph(
+    (description, params): (State<Arc<GraphDescription>>, Query<GraphParams>),
+) -> HttpResponse {
+    trace!("graph request: {:?}", params);
+
+    let graph = match description.to_graph(&params) {
+        Ok(g) => g,
+        Err(e) => {
+            error!("failed to build graph: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    match serde_json::to_string(&graph) {
+        Ok(json) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(json),
+        Err(e) => {
+            error!("failed to serialize graph: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
@@ -0,0 +1,352 @@
+//! Blocking health checks.
+
+use crate::config::{HealthConfig, TlsInput};
+use crate::http::{self, ClientConfig, TlsConfig};
+use actix::prelude::*;
+use failure::{Fallible, ResultExt};
+use futures::Future;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::{fs, thread, time};
+use tokio::runtime::current_thread::Runtime;
+use url::Url;
+
+/// systemctl binary.
+static SYSTEMCTL_BIN: &str = "systemctl";
+
+/// User-Agent for HTTP checks.
+static USER_AGENT: &str = concat!("zincati/", env!("CARGO_PKG_VERSION"), " (health check)");
+
+/// Polling interval while waiting for an executable check.
+static POLL_INTERVAL_MS: u64 = 100;
+
+/// A single health check.
+#[derive(Clone, Debug)]
+enum Check {
+    /// Executable which must exit successfully.
+    Executable(PathBuf),
+    /// systemd unit which must be active.
+    UnitActive(String),
+    /// Maximum number of failed systemd units.
+    FailedUnits(u32),
+    /// Local HTTP endpoint which must reply successfully.
+    Http(String),
+}
+
+impl Check {
+    /// Run this check, failing if unhealthy.
+    fn run(&self, timeout: time::Duration) -> Fallible<()> {
+        match self {
+            Check::Executable(path) => {
+                let mut cmd = Command::new(path);
+                cmd.stdin(Stdio::null());
+                run_with_timeout(cmd, timeout)
+            }
+            Check::UnitActive(unit) => {
+                let mut cmd = Command::new(SYSTEMCTL_BIN);
+                cmd.args(["is-active", "--quiet", unit]);
+                run_with_timeout(cmd, timeout).context("unit not active")?;
+                Ok(())
+            }
+            Check::FailedUnits(max) => {
+                let mut cmd = Command::new(SYSTEMCTL_BIN);
+                cmd.args(["list-units", "--state=failed", "--no-legend", "--plain"]);
+                let out = output_with_timeout(cmd, timeout).context("failed to list units")?;
+                let failed = count_failed_units(&out);
+                if failed > *max as usize {
+                    bail!("{} failed units, at most {} allowed", failed, max);
+                }
+                Ok(())
+            }
+            Check::Http(url) => {
+                // Endpoints are local (see `HealthConfig`), so they are probed
+                // without the proxy and pins configured for remote services.
+                let url = Url::parse(url)?;
+                let tls = TlsConfig::try_from_input(TlsInput::default(), false)?;
+                let client_cfg = ClientConfig {
+                    proxy: None,
+                    no_proxy: vec![],
+                    connect_timeout: timeout,
+                    request_timeout: timeout,
+                };
+                let client = client_cfg.build(&tls, USER_AGENT)?;
+                let no_query: &[(&str, &str)] = &[];
+                let req = client.get(url, &no_query).and_then(http::check_status);
+                Runtime::new()?.block_on(req)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Check::Executable(path) => write!(f, "executable '{}'", path.display()),
+            Check::UnitActive(unit) => write!(f, "unit '{}'", unit),
+            Check::FailedUnits(_) => write!(f, "failed units"),
+            Check::Http(url) => write!(f, "endpoint '{}'", url),
+        }
+    }
+}
+
+/// Blocking checker, running checks on a sync arbiter.
+#[derive(Debug)]
+pub struct BlockingChecker {
+    cfg: HealthConfig,
+}
+
+impl BlockingChecker {
+    pub(crate) fn new(cfg: HealthConfig) -> Self {
+        Self { cfg }
+    }
+
+    /// All configured checks, executables first.
+    fn checks(&self) -> Vec<Check> {
+        let mut checks: Vec<Check> = match find_executables(&self.cfg.checks_dir) {
+            Ok(paths) => paths.into_iter().map(Check::Executable).collect(),
+            Err(e) => {
+                warn!("skipping health checks directory: {}", e);
+                vec![]
+            }
+        };
+        checks.extend(self.cfg.units.iter().cloned().map(Check::UnitActive));
+        checks.extend(self.cfg.max_failed_units.map(Check::FailedUnits));
+        checks.extend(self.cfg.http_endpoints.iter().cloned().map(Check::Http));
+        checks
+    }
+}
+
+impl Actor for BlockingChecker {
+    type Context = SyncContext<Self>;
+
+    fn started(&mut self, _ctx: &mut Self::Context) {
+        trace!("blocking health checker started");
+    }
+}
+
+/// Blocking request: run all checks, returning failures.
+pub(crate) struct RunAll {}
+
+impl Message for RunAll {
+    type Result = Vec<String>;
+}
+
+impl Handler<RunAll> for BlockingChecker {
+    type Result = MessageResult<RunAll>;
+
+    fn handle(&mut self, _msg: RunAll, _ctx: &mut Self::Context) -> Self::Result {
+        let mut failures = vec![];
+        for check in self.checks() {
+            match check.run(self.cfg.check_timeout) {
+                Ok(_) => debug!("health check {} passed", check),
+                Err(e) => {
+                    let reason = e
+                        .iter_chain()
+                        .map(|c| c.to_string())
+                        .collect::<Vec<_>>()
+                        .join(": ");
+                    failures.push(format!("{}: {}", check, reason));
+                }
+            }
+        }
+        MessageResult(failures)
+    }
+}
+
+/// List executable regular files in a directory, sorted by name.
+///
+/// A missing directory means no checks.
+fn find_executables(dir: &Path) -> Fallible<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        res => res.context(format!("failed to read '{}'", dir.display()))?,
+    };
+
+    let mut paths = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        let meta = fs::metadata(&path)?;
+        if meta.is_file() && meta.permissions().mode() & 0o111 != 0 {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Count failed units, from `systemctl list-units --no-legend` output.
+fn count_failed_units(output: &[u8]) -> usize {
+    String::from_utf8_lossy(output)
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .count()
+}
+
+/// Run a command to completion, killing it on timeout.
+fn run_with_timeout(mut cmd: Command, timeout: time::Duration) -> Fallible<()> {
+    cmd.stdout(Stdio::null());
+    wait_with_timeout(cmd, timeout)?;
+    Ok(())
+}
+
+/// Run a command to completion and capture its output, killing it on timeout.
+fn output_with_timeout(mut cmd: Command, timeout: time::Duration) -> Fallible<Vec<u8>> {
+    cmd.stdout(Stdio::piped());
+    wait_with_timeout(cmd, timeout)
+}
+
+/// Spawn a command and wait for it, returning its output if piped.
+///
+/// The timeout also bounds reading the output, which may be held open by
+/// processes the command left behind.
+fn wait_with_timeout(mut cmd: Command, timeout: time::Duration) -> Fallible<Vec<u8>> {
+    trace!("running {:?}", cmd);
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .context(format!("failed to run {:?}", cmd))?;
+
+    // Drain output concurrently, so that the child never blocks on a full pipe.
+    // The reader is never joined, as it may block for as long as the pipe is open.
+    let reader = child.stdout.take().map(|mut stdout| {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut out = vec![];
+            let _ = tx.send(stdout.read_to_end(&mut out).map(|_| out));
+        });
+        rx
+    });
+
+    let start = time::Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            if !status.success() {
+                bail!("exited with {}", status);
+            }
+            break;
+        }
+        if start.elapsed() >= timeout {
+            // Best-effort, the child may have just exited.
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!("timed out after {}s", timeout.as_secs());
+        }
+        thread::sleep(time::Duration::from_millis(POLL_INTERVAL_MS));
+    }
+
+    let out = match reader {
+        Some(rx) => {
+            let poll = time::Duration::from_millis(POLL_INTERVAL_MS);
+            let remaining = timeout.checked_sub(start.elapsed()).unwrap_or_default();
+            rx.recv_timeout(remaining.max(poll))
+                .map_err(|_| format_err!("timed out reading output"))?
+                .context("failed to read output")?
+        }
+        None => vec![],
+    };
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shell(script: &str) -> Command {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", script]);
+        cmd
+    }
+
+    #[test]
+    fn test_find_executables() {
+        let tmp = tempfile::tempdir().unwrap();
+        let create = |name: &str, mode: u32| {
+            let path = tmp.path().join(name);
+            fs::write(&path, "#!/bin/sh\n").unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        };
+        create("20-second", 0o755);
+        create("10-first", 0o700);
+        create("30-not-executable", 0o644);
+        fs::create_dir(tmp.path().join("40-directory")).unwrap();
+
+        let found = find_executables(tmp.path()).unwrap();
+        let names: Vec<_> = found.iter().filter_map(|p| p.file_name()).collect();
+        assert_eq!(names, vec!["10-first", "20-second"]);
+
+        // A missing directory means no checks.
+        let missing = find_executables(&tmp.path().join("missing")).unwrap();
+        assert!(missing.is_empty());
+    }
+
+    #[test]
+    fn test_wait_with_timeout() {
+        let timeout = time::Duration::from_secs(5);
+        let out = output_with_timeout(shell("echo healthy"), timeout).unwrap();
+        assert_eq!(out, b"healthy\n");
+        run_with_timeout(shell("exit 3"), timeout).unwrap_err();
+    }
+
+    #[test]
+    fn test_wait_with_timeout_kill() {
+        // The child is killed on timeout, and reported as failed.
+        let tmp = tempfile::tempdir().unwrap();
+        let marker = tmp.path().join("marker");
+        let script = format!("sleep 1 && touch '{}'", marker.display());
+        let start = time::Instant::now();
+        let err = run_with_timeout(shell(&script), time::Duration::from_millis(300));
+        assert!(err.unwrap_err().to_string().contains("timed out"));
+        assert!(start.elapsed() < time::Duration::from_secs(1));
+        thread::sleep(time::Duration::from_millis(1500));
+        assert!(!marker.exists());
+
+        // A grandchild holding the output open does not block either.
+        let start = time::Instant::now();
+        let err = output_with_timeout(
+            shell("sleep 30 & echo forked"),
+            time::Duration::from_millis(300),
+        );
+        assert!(err.unwrap_err().to_string().contains("timed out"));
+        assert!(start.elapsed() < time::Duration::from_secs(10));
+    }
+
+    /// Serve a single HTTP reply on localhost, returning the endpoint.
+    fn local_endpoint(status_line: &'static str) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            use std::io::Write;
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf);
+            let reply = format!("{}\r\nContent-Length: 0\r\n\r\n", status_line);
+            let _ = stream.write_all(reply.as_bytes());
+        });
+        format!("http://127.0.0.1:{}/healthz", port)
+    }
+
+    #[test]
+    fn test_http_check() {
+        let timeout = time::Duration::from_secs(5);
+        let healthy = Check::Http(local_endpoint("HTTP/1.1 200 OK"));
+        healthy.run(timeout).unwrap();
+        let unhealthy = Check::Http(local_endpoint("HTTP/1.1 503 Service Unavailable"));
+        let err = unhealthy.run(timeout).unwrap_err();
+        assert!(err.to_string().contains("503"), "{}", err);
+    }
+
+    #[test]
+    fn test_count_failed_units() {
+        let output = b"foo.service loaded failed failed Foo daemon\n\
+                       bar.mount loaded failed failed /bar\n\
+                       \n";
+        assert_eq!(count_failed_units(output), 2);
+        assert_eq!(count_failed_units(b""), 0);
+        assert_eq!(count_failed_units(b"\n  \n"), 0);
+    }
+}
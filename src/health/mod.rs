@@ -0,0 +1,106 @@
+//! Post-reboot health checks.
+//!
+//! This module contains `HealthChecker`, which runs health checks
+//! after rebooting into an update, before the agent reports steady
+//! state. Checks are both executables in a drop-in directory and
+//! built-in ones (active systemd units, failed units count, local
+//! HTTP endpoints). Checks are blocking, so they are run on a sync
+//! arbiter.
+
+mod checks;
+
+use crate::config::HealthConfig;
+use actix::prelude::*;
+use checks::BlockingChecker;
+use failure::Error;
+use futures::prelude::*;
+use lazy_static::lazy_static;
+use std::sync;
+use std::time;
+
+lazy_static! {
+    pub(crate) static ref CONFIGURED: sync::RwLock<Option<HealthChecker>> = sync::RwLock::default();
+}
+
+/// Configure health checker.
+pub(crate) fn configure(cfg: HealthConfig) -> failure::Fallible<()> {
+    let checker = HealthChecker {
+        cfg,
+        blocking_checker: None,
+    };
+    let mut static_cfg = CONFIGURED.try_write().unwrap();
+    *static_cfg = Some(checker);
+    Ok(())
+}
+
+/// Main actor for running health checks.
+#[derive(Clone, Debug)]
+pub struct HealthChecker {
+    cfg: HealthConfig,
+    blocking_checker: Option<Addr<BlockingChecker>>,
+}
+
+impl Default for HealthChecker {
+    fn default() -> Self {
+        let cfg = CONFIGURED.try_read().expect("poisoned lock");
+        cfg.clone().expect("not configured")
+    }
+}
+
+impl Supervised for HealthChecker {}
+impl SystemService for HealthChecker {}
+impl Actor for HealthChecker {
+    type Context = Context<Self>;
+
+    fn started(&mut self, _ctx: &mut Self::Context) {
+        let cfg = self.cfg.clone();
+        let addr = actix::sync::SyncArbiter::start(1, move || BlockingChecker::new(cfg.clone()));
+        self.blocking_checker = Some(addr);
+
+        trace!("health checker started");
+    }
+}
+
+/// Outcome of a round of health checks.
+#[derive(Clone, Debug)]
+pub(crate) struct HealthReport {
+    /// Descriptions of failed checks.
+    pub(crate) failures: Vec<String>,
+    /// Whether the grace period for checks to pass is over.
+    pub(crate) grace_expired: bool,
+}
+
+impl HealthReport {
+    /// Whether all checks passed.
+    pub(crate) fn is_healthy(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Health request: run all checks, for a boot verified at the given time.
+pub(crate) struct RunChecks {
+    pub(crate) since: time::SystemTime,
+}
+
+impl Message for RunChecks {
+    type Result = Result<HealthReport, Error>;
+}
+
+impl Handler<RunChecks> for HealthChecker {
+    type Result = Box<dyn Future<Item = HealthReport, Error = Error>>;
+
+    fn handle(&mut self, msg: RunChecks, _ctx: &mut Self::Context) -> Self::Result {
+        let grace_expired = msg.since.elapsed().unwrap_or_default() >= self.cfg.grace_period;
+        let report = self
+            .blocking_checker
+            .clone()
+            .unwrap()
+            .send(checks::RunAll {})
+            .from_err()
+            .map(move |failures| HealthReport {
+                failures,
+                grace_expired,
+            });
+        Box::new(report)
+    }
+}
@@ -0,0 +1,15 @@
+//! HTTP client helpers.
+//!
+//! This module contains shared logic for building the HTTP clients
+//! used to talk to remote services (Cincinnati and lock managers).
+
+mod client;
+mod connect;
+mod status;
+mod tls;
+
+pub(crate) use client::{
+    read_body_limited, redact_url, user_agent, Client, ClientConfig, Response,
+};
+pub(crate) use status::{check_status, StatusError};
+pub(crate) use tls::TlsConfig;
@@ -0,0 +1,47 @@
+//! HTTP response status checks.
+
+use super::Response;
+use failure::{Fail, Fallible};
+use hyper::header::RETRY_AFTER;
+use hyper::StatusCode;
+use std::{fmt, time};
+
+/// Non-successful HTTP response from a remote service.
+#[derive(Debug)]
+pub(crate) struct StatusError {
+    pub(crate) url: String,
+    pub(crate) status: StatusCode,
+    /// Delay requested by the remote via `Retry-After`, if any.
+    pub(crate) retry_after: Option<time::Duration>,
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HTTP status {} from '{}'", self.status, self.url)
+    }
+}
+
+impl Fail for StatusError {}
+
+/// Ensure a response is successful, capturing any `Retry-After` hint otherwise.
+pub(crate) fn check_status(resp: Response) -> Fallible<Response> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+
+    // Only the delay-seconds form is supported, HTTP dates are ignored.
+    let retry_after = resp
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(time::Duration::from_secs);
+
+    let err = StatusError {
+        url: resp.url().to_string(),
+        status,
+        retry_after,
+    };
+    Err(err.into())
+}
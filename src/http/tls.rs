@@ -0,0 +1,361 @@
+//! TLS configuration for outbound HTTP clients.
+//!
+//! Server certificates are verified against the system CA bundle, plus any
+//! configured CA bundles. Server public keys can be pinned on top of that,
+//! in which case the certificate chain sent by the server must contain one
+//! of the pinned keys.
+
+use crate::config::TlsInput;
+use failure::{Fallible, ResultExt};
+use ring::digest;
+use rustls::internal::pemfile;
+use rustls::{
+    Certificate, ClientConfig, ProtocolVersion, RootCertStore, ServerCertVerified,
+    ServerCertVerifier, TLSError,
+};
+use serde::{Serialize, Serializer};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::{fmt, fs};
+
+/// Well-known locations of the system CA bundle, in lookup order.
+static SYSTEM_CA_BUNDLES: &[&str] = &[
+    // Fedora, RHEL.
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    // Debian, Ubuntu.
+    "/etc/ssl/certs/ca-certificates.crt",
+];
+
+/// Prefix of SPKI pins, naming their digest algorithm.
+static SPKI_PIN_PREFIX: &str = "sha256/";
+
+/// Minimum TLS protocol version.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub(crate) enum TlsVersion {
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    /// Parse a TLS version from its configuration label (e.g. "1.2").
+    fn try_parse(input: &str) -> Fallible<Self> {
+        let version = match input {
+            "" | "1.2" => TlsVersion::Tls12,
+            "1.3" => TlsVersion::Tls13,
+            "1.0" | "1.1" => bail!("TLS {} is not supported, the minimum is TLS 1.2", input),
+            x => bail!("unknown TLS version '{}'", x),
+        };
+        Ok(version)
+    }
+
+    /// Protocol versions which can be negotiated.
+    fn allowed(self) -> Vec<ProtocolVersion> {
+        match self {
+            TlsVersion::Tls12 => vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
+            TlsVersion::Tls13 => vec![ProtocolVersion::TLSv1_3],
+        }
+    }
+}
+
+/// Validated TLS configuration for an HTTP client.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct TlsConfig {
+    /// System CA bundle, if trusted and found.
+    pub(crate) system_ca_bundle: Option<PathBuf>,
+    /// Additional CA bundles to trust.
+    pub(crate) ca_bundles: Vec<PathBuf>,
+    /// Client certificate and key for mutual TLS.
+    #[serde(serialize_with = "serialize_client_identity")]
+    pub(crate) client_identity: Option<(PathBuf, PathBuf)>,
+    /// Minimum TLS version.
+    pub(crate) min_version: TlsVersion,
+    /// Pinned server public keys.
+    pub(crate) spki_pins: Vec<String>,
+    /// rustls configuration, shared by all connections.
+    #[serde(skip)]
+    rustls: RustlsConfig,
+}
+
+/// rustls client configuration, which does not implement `Debug`.
+#[derive(Clone)]
+struct RustlsConfig(Arc<ClientConfig>);
+
+impl fmt::Debug for RustlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ClientConfig { .. }")
+    }
+}
+
+impl TlsConfig {
+    /// Validate inputs and load all referenced PEM files.
+    ///
+    /// Trusted CA certificates are only required if the service is
+    /// reached over `https`.
+    pub(crate) fn try_from_input(cfg: TlsInput, https: bool) -> Fallible<Self> {
+        let mut rustls = ClientConfig::new();
+        let min_version = TlsVersion::try_parse(&cfg.min_tls_version)?;
+        rustls.versions = min_version.allowed();
+
+        let system_ca_bundle = if cfg.system_ca_bundle.unwrap_or(true) {
+            find_system_ca_bundle()
+        } else {
+            None
+        };
+        if let Some(ref path) = system_ca_bundle {
+            let certs = read_certs(path)?;
+            // Bundles shipped by distributions may carry some certificates
+            // which webpki cannot parse, those are skipped.
+            let skipped = certs
+                .iter()
+                .filter(|c| rustls.root_store.add(c).is_err())
+                .count();
+            if skipped > 0 {
+                debug!(
+                    "skipped {} unsupported certificate(s) in '{}'",
+                    skipped,
+                    path.display()
+                );
+            }
+        }
+
+        let mut ca_bundles = vec![];
+        for path in cfg.ca_bundles {
+            let path = PathBuf::from(path);
+            for cert in read_certs(&path)? {
+                rustls
+                    .root_store
+                    .add(&cert)
+                    .map_err(|e| format_err!("{:?}", e))
+                    .context(format!("invalid CA certificate in '{}'", path.display()))?;
+            }
+            ca_bundles.push(path);
+        }
+        if https && rustls.root_store.is_empty() {
+            bail!("no trusted CA certificates, neither system nor configured ones");
+        }
+
+        let client_identity = match (cfg.client_cert.is_empty(), cfg.client_key.is_empty()) {
+            (true, true) => None,
+            (false, false) => {
+                let cert = PathBuf::from(cfg.client_cert);
+                let key = PathBuf::from(cfg.client_key);
+                let chain = read_certs(&cert)?;
+                let key_der = read_private_key(&key)?;
+                // rustls panics on unsupported keys, check them beforehand.
+                rustls::sign::any_supported_type(&key_der).map_err(|_| {
+                    format_err!("unsupported client key type in '{}'", key.display())
+                })?;
+                rustls.set_single_client_cert(chain, key_der);
+                Some((cert, key))
+            }
+            _ => bail!("client certificate and key must be configured together"),
+        };
+
+        let mut pins = vec![];
+        for pin in &cfg.spki_pins {
+            pins.push(parse_spki_pin(pin)?);
+        }
+        if !pins.is_empty() {
+            let verifier = PinningVerifier {
+                webpki: ClientConfig::new(),
+                pins,
+            };
+            rustls
+                .dangerous()
+                .set_certificate_verifier(Arc::new(verifier));
+        }
+
+        let tls = Self {
+            system_ca_bundle,
+            ca_bundles,
+            client_identity,
+            min_version,
+            spki_pins: cfg.spki_pins,
+            rustls: RustlsConfig(Arc::new(rustls)),
+        };
+        Ok(tls)
+    }
+
+    /// Return the rustls configuration for client connections.
+    pub(crate) fn client_config(&self) -> Arc<ClientConfig> {
+        self.rustls.0.clone()
+    }
+}
+
+/// Serialize a client identity, without the location of its private key.
+fn serialize_client_identity<S: Serializer>(
+    identity: &Option<(PathBuf, PathBuf)>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    identity
+        .as_ref()
+        .map(|(cert, _)| cert)
+        .serialize(serializer)
+}
+
+/// Server certificate verifier, requiring a pinned key in the chain.
+///
+/// Pins are matched against the certificates sent by the server, i.e.
+/// the leaf and intermediate ones.
+struct PinningVerifier {
+    /// Default configuration, holding the stock webpki verifier.
+    webpki: ClientConfig,
+    /// SHA-256 digests of pinned SubjectPublicKeyInfo.
+    pins: Vec<Vec<u8>>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        dns_name: webpki::DNSNameRef,
+        ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let verified = self.webpki.get_verifier().verify_server_cert(
+            roots,
+            presented_certs,
+            dns_name,
+            ocsp_response,
+        )?;
+
+        let pinned = presented_certs
+            .iter()
+            .filter_map(|cert| spki_digest(&cert.0))
+            .any(|digest| self.pins.contains(&digest));
+        if !pinned {
+            let msg = "no pinned public key in server certificate chain";
+            return Err(TLSError::General(msg.to_string()));
+        }
+
+        Ok(verified)
+    }
+}
+
+/// Find the system CA bundle, if any.
+fn find_system_ca_bundle() -> Option<PathBuf> {
+    let found = SYSTEM_CA_BUNDLES
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| path.is_file());
+    if found.is_none() {
+        warn!("no system CA bundle found");
+    }
+    found
+}
+
+/// Read all certificates from a PEM file.
+fn read_certs(path: &Path) -> Fallible<Vec<Certificate>> {
+    let file = fs::File::open(path).context(format!("failed to open '{}'", path.display()))?;
+    let certs = pemfile::certs(&mut BufReader::new(file))
+        .map_err(|_| format_err!("invalid PEM file '{}'", path.display()))?;
+    if certs.is_empty() {
+        bail!("no certificates found in '{}'", path.display());
+    }
+    Ok(certs)
+}
+
+/// Read a private key (PKCS#8 or PKCS#1) from a PEM file.
+fn read_private_key(path: &Path) -> Fallible<rustls::PrivateKey> {
+    let content = fs::read(path).context(format!("failed to read '{}'", path.display()))?;
+    let pkcs8 = pemfile::pkcs8_private_keys(&mut content.as_slice());
+    let rsa = pemfile::rsa_private_keys(&mut content.as_slice());
+    pkcs8
+        .into_iter()
+        .chain(rsa)
+        .flatten()
+        .next()
+        .ok_or_else(|| format_err!("no private key found in '{}'", path.display()))
+}
+
+/// Parse a configured SPKI pin into a SHA-256 digest.
+fn parse_spki_pin(pin: &str) -> Fallible<Vec<u8>> {
+    if !pin.starts_with(SPKI_PIN_PREFIX) {
+        bail!("SPKI pin '{}' must start with '{}'", pin, SPKI_PIN_PREFIX);
+    }
+    let digest = base64::decode(&pin[SPKI_PIN_PREFIX.len()..])
+        .context(format!("invalid base64 in SPKI pin '{}'", pin))?;
+    if digest.len() != digest::SHA256_OUTPUT_LEN {
+        bail!("SPKI pin '{}' is not a SHA-256 digest", pin);
+    }
+    Ok(digest)
+}
+
+/// SHA-256 digest of the SubjectPublicKeyInfo of a DER certificate.
+fn spki_digest(cert: &[u8]) -> Option<Vec<u8>> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    let digest = digest::digest(&digest::SHA256, cert.public_key().raw);
+    Some(digest.as_ref().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Self-signed ECDSA P-256 certificate for `zincati.test`.
+    static CERT_PEM: &str = "-----BEGIN CERTIFICATE-----
+MIIBhTCCASugAwIBAgIUDOJAslVZLbdJUWyVI+SnDEyCBm0wCgYIKoZIzj0EAwIw
+FzEVMBMGA1UEAwwMemluY2F0aS50ZXN0MCAXDTI2MTAxODE2MzM0MFoYDzIxMjYw
+OTI0MTYzMzQwWjAXMRUwEwYDVQQDDAx6aW5jYXRpLnRlc3QwWTATBgcqhkjOPQIB
+BggqhkjOPQMBBwNCAASaWCfzSDhmS5vrZb93cPZ5kstxFt5C+UGh8V+yM7VI9fu7
+sjSGxgkdk3wjcSWRjveGXjX36e0EvgDv5sCsmF0Ao1MwUTAdBgNVHQ4EFgQUe1xP
+5uyuW4RmWKmAeFarGZlOv/AwHwYDVR0jBBgwFoAUe1xP5uyuW4RmWKmAeFarGZlO
+v/AwDwYDVR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNIADBFAiEA3XeR1zh+QAv4
+2RuCQ6HnnTLQqXCAmYzNOUqRnniqDpICIEMqueKP8mnBLf7whvnpKzrmWUvuGpb5
++HnoS9Ls3FH8
+-----END CERTIFICATE-----
+";
+
+    /// Pin of the certificate above, as computed by:
+    /// `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der |
+    /// openssl dgst -sha256 -binary | base64`
+    static CERT_PIN: &str = "sha256/SsFUxuSLYB5pwGmRF/LA29EgibQVe4G5gD1YcaCfe5Y=";
+
+    fn cert_der() -> Vec<u8> {
+        let certs = pemfile::certs(&mut CERT_PEM.as_bytes()).unwrap();
+        certs[0].0.clone()
+    }
+
+    #[test]
+    fn test_spki_digest() {
+        let der = cert_der();
+        let pin = parse_spki_pin(CERT_PIN).unwrap();
+        assert_eq!(spki_digest(&der), Some(pin));
+
+        // Truncated certificates are not parsed.
+        assert_eq!(spki_digest(&der[..der.len() / 2]), None);
+        assert_eq!(spki_digest(&[]), None);
+    }
+
+    #[test]
+    fn test_parse_spki_pin() {
+        parse_spki_pin(CERT_PIN).unwrap();
+        parse_spki_pin(&CERT_PIN[SPKI_PIN_PREFIX.len()..]).unwrap_err();
+        parse_spki_pin("sha256/not base64").unwrap_err();
+        parse_spki_pin("sha256/AAAA").unwrap_err();
+    }
+
+    #[test]
+    fn test_require_roots() {
+        let input = TlsInput {
+            system_ca_bundle: Some(false),
+            ..TlsInput::default()
+        };
+        TlsConfig::try_from_input(input.clone(), true).unwrap_err();
+        let tls = TlsConfig::try_from_input(input, false).unwrap();
+        assert_eq!(tls.system_ca_bundle, None);
+        assert!(tls.ca_bundles.is_empty());
+    }
+
+    #[test]
+    fn test_min_tls_version() {
+        assert_eq!(TlsVersion::try_parse("").unwrap(), TlsVersion::Tls12);
+        assert_eq!(TlsVersion::try_parse("1.3").unwrap(), TlsVersion::Tls13);
+        TlsVersion::try_parse("1.1").unwrap_err();
+        TlsVersion::try_parse("tls13").unwrap_err();
+        assert_eq!(TlsVersion::Tls13.allowed(), vec![ProtocolVersion::TLSv1_3]);
+    }
+}
@@ -5,11 +5,21 @@
 //! bridges between a Cincinnati server and the rpm-ostree daemon,
 //! implementing conditional strategies for finalization.
 //!
-//! It is made of three actors passing action-requests to each
+//! It is made of four actors passing action-requests to each
 //! other:
 //!  * `UpdateAgent` - main agent state-machine, with support for several user-strategies.
 //!  * `CincinnatiClient` - HTTP client to Cincinnati, periodic scraper.
 //!  * `RpmOstreeClient` - DBus client to rpm-ostree daemon.
+//!  * `HealthChecker` - post-reboot health checks runner.
+//!
+//! The running agent can be queried and steered through a local
+//! control API, served on a Unix socket, e.g. via the `status`, `check`,
+//! `pause`, `resume` and `finalize-now` subcommands, and optionally
+//! through DBus as `org.coreos.zincati1`. Metrics are exposed there in
+//! Prometheus format, and optionally written to a textfile.
+//!
+//! It also bundles a minimal Cincinnati graph server (`graph-server`
+//! subcommand), for testing and small deployments.
 
 extern crate cincinnati as libcincinnati;
 extern crate env_logger;
@@ -19,42 +29,142 @@ extern crate futures;
 extern crate lazy_static;
 #[macro_use]
 extern crate log;
-extern crate reqwest;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate dbus;
 extern crate dbus_tokio;
 extern crate serde_json;
+#[macro_use]
 extern crate structopt;
+extern crate url;
 extern crate url_serde;
 extern crate uuid;
 
 mod cincinnati;
 mod config;
+mod control;
+mod graph_server;
+mod health;
+mod http;
+mod metrics;
 mod rpm_ostree;
 mod strategy;
+#[cfg(test)]
+mod testing;
 mod update_agent;
 
 use crate::cincinnati::CincinnatiClient;
-use crate::config::AgentConfig;
+use crate::config::{AgentConfig, ControlConfig};
+use crate::control::ClientCommand;
+use crate::health::HealthChecker;
 use crate::rpm_ostree::RpmOstreeClient;
-use crate::update_agent::UpdateAgent;
+use crate::update_agent::{StatusWatch, UpdateAgent};
 use actix::prelude::*;
 use failure::Fallible;
+use structopt::StructOpt;
+
+/// Configuration directories, in increasing priority order.
+static CONFIG_DIRS: &[&str] = &["/usr/lib", "/run", "/etc"];
+
+/// Command-line options.
+#[derive(Debug, StructOpt)]
+#[structopt(name = "zincati")]
+struct CliOptions {
+    #[structopt(subcommand)]
+    cmd: Option<CliCommand>,
+}
+
+/// Subcommands.
+#[derive(Debug, StructOpt)]
+enum CliCommand {
+    /// Run the update agent (default)
+    #[structopt(name = "agent")]
+    Agent,
+    /// Serve a static Cincinnati graph
+    #[structopt(name = "graph-server")]
+    GraphServer(graph_server::CliOptions),
+    /// Manage the local blocklist of bad releases
+    #[structopt(name = "blocklist")]
+    Blocklist(update_agent::BlocklistCommand),
+    /// Show the running agent status
+    #[structopt(name = "status")]
+    Status {
+        /// Print status as JSON
+        #[structopt(long = "json")]
+        json: bool,
+    },
+    /// Check for updates now
+    #[structopt(name = "check")]
+    Check,
+    /// Pause updates, until resumed
+    #[structopt(name = "pause")]
+    Pause,
+    /// Resume updates
+    #[structopt(name = "resume")]
+    Resume,
+    /// Finalize the staged update and reboot now, bypassing the update strategy
+    #[structopt(name = "finalize-now")]
+    FinalizeNow,
+    /// Clear an abandoned update, keeping it blocklisted
+    #[structopt(name = "clear-abandoned")]
+    ClearAbandoned,
+}
 
 fn main() -> Fallible<()> {
     env_logger::Builder::from_default_env().try_init()?;
+    let opts = CliOptions::from_args();
+
+    match opts.cmd {
+        None | Some(CliCommand::Agent) => run_agent(),
+        Some(CliCommand::GraphServer(cfg)) => graph_server::run(cfg),
+        Some(CliCommand::Blocklist(cmd)) => {
+            let blocklist = config::read_blocklist_config(CONFIG_DIRS.to_vec())?;
+            update_agent::run_blocklist_cli(&blocklist, cmd)
+        }
+        Some(CliCommand::Status { json }) => run_client(ClientCommand::Status { json }),
+        Some(CliCommand::Check) => run_client(ClientCommand::Check),
+        Some(CliCommand::Pause) => run_client(ClientCommand::Pause),
+        Some(CliCommand::Resume) => run_client(ClientCommand::Resume),
+        Some(CliCommand::FinalizeNow) => run_client(ClientCommand::FinalizeNow),
+        Some(CliCommand::ClearAbandoned) => run_client(ClientCommand::ClearAbandoned),
+    }
+}
+
+/// Send a request to the running agent.
+fn run_client(cmd: ClientCommand) -> Fallible<()> {
+    let cfg = ControlConfig::read_config(CONFIG_DIRS.to_vec())?;
+    control::run_client_cli(&cfg, cmd)
+}
+
+/// Run the update agent.
+fn run_agent() -> Fallible<()> {
     info!("starting zincati");
 
     // Configure whole application.
-    {
-        let dirs = vec!["/usr/lib", "/run", "/etc"];
-        let cfg = AgentConfig::read_config(dirs)?;
-        cincinnati::configure(cfg.cincinnati, cfg.identity.clone())?;
-        rpm_ostree::configure()?;
-        update_agent::configure(cfg.strategy, cfg.identity)?;
-    }
+    let status_watch = StatusWatch::default();
+    let (control_cfg, config_dump) = {
+        let mut cfg = AgentConfig::read_config(CONFIG_DIRS.to_vec())?;
+        // The booted stream is part of the User-Agent of HTTP clients, so
+        // it is looked up before building them.
+        cfg.identity.booted_stream = rpm_ostree::booted_stream(&cfg.rpm_ostree);
+        cfg.strategy.build_clients(&cfg.identity)?;
+        let config_dump = serde_json::to_value(&cfg)?;
+        let control_cfg = cfg.control.clone();
+        cincinnati::configure(cfg.cincinnati, cfg.identity.clone(), cfg.blocklist.clone())?;
+        rpm_ostree::configure(cfg.rpm_ostree)?;
+        health::configure(cfg.health)?;
+        update_agent::configure(
+            cfg.strategy,
+            cfg.identity,
+            cfg.scheduler,
+            cfg.state_file,
+            cfg.blocklist,
+            cfg.metrics_textfile,
+            status_watch.clone(),
+        )?;
+        (control_cfg, config_dump)
+    };
 
     let sys = actix::System::new("zincati");
 
@@ -76,6 +186,15 @@ fn main() -> Fallible<()> {
         Supervisor::start_in_arbiter(&cincinnati_arbiter, |_| CincinnatiClient::default());
     System::current().registry().set(cincinnati_supervisor);
 
+    // Start health checker in its own thread and event loop.
+    let health_arbiter = Arbiter::builder()
+        .name("health")
+        .stop_system_on_panic(true)
+        .build();
+    let health_supervisor =
+        Supervisor::start_in_arbiter(&health_arbiter, |_| HealthChecker::default());
+    System::current().registry().set(health_supervisor);
+
     // Start update agent in its own thread and event loop.
     let agent_arbiter = Arbiter::builder()
         .name("update_agent")
@@ -85,6 +204,12 @@ fn main() -> Fallible<()> {
         Supervisor::start_in_arbiter(&agent_arbiter, |_| UpdateAgent::default());
     System::current().registry().set(agent_supervisor);
 
+    // Serve the control API, and optionally the DBus interface.
+    let dbus_service = control_cfg.dbus_service;
+    control::start(control_cfg, config_dump)?;
+    let agent_addr = System::current().registry().get::<UpdateAgent>();
+    control::start_dbus_service(dbus_service, agent_addr, status_watch)?;
+
     sys.run();
     Ok(())
 }
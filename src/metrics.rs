@@ -0,0 +1,286 @@
+//! Agent metrics, in Prometheus text format.
+//!
+//! Counters and timestamps are recorded in a process-wide registry by
+//! the actors which observe them. State-derived metrics (current state,
+//! available update) are taken from the agent status at render time.
+
+use crate::update_agent::{AgentStatus, Subsystem, UpdateAgentState};
+use failure::{Fallible, ResultExt};
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::sync;
+use std::time;
+
+lazy_static! {
+    static ref REGISTRY: sync::Mutex<Metrics> = sync::Mutex::default();
+}
+
+/// Recorded counters and timestamps.
+#[derive(Debug, Default)]
+struct Metrics {
+    /// Graph requests to Cincinnati.
+    graph_fetches: u64,
+    /// Errors, per subsystem.
+    errors: BTreeMap<Subsystem, u64>,
+    /// Finalizations denied by the lock manager.
+    lock_denials: u64,
+    /// Last successful staging, in seconds since UNIX epoch.
+    last_stage: Option<u64>,
+    /// Last successful finalization, in seconds since UNIX epoch.
+    last_finalize: Option<u64>,
+}
+
+/// Record a graph request to Cincinnati.
+pub(crate) fn record_graph_fetch() {
+    with_registry(|m| m.graph_fetches += 1);
+}
+
+/// Record an error in a subsystem.
+pub(crate) fn record_error(subsystem: Subsystem) {
+    with_registry(|m| *m.errors.entry(subsystem).or_insert(0) += 1);
+}
+
+/// Record a finalization denied by the lock manager.
+pub(crate) fn record_lock_denial() {
+    with_registry(|m| m.lock_denials += 1);
+}
+
+/// Record a successful staging.
+pub(crate) fn record_stage() {
+    let now = epoch_secs();
+    with_registry(|m| m.last_stage = Some(now));
+}
+
+/// Record a successful finalization.
+pub(crate) fn record_finalize() {
+    let now = epoch_secs();
+    with_registry(|m| m.last_finalize = Some(now));
+}
+
+fn with_registry<F: FnOnce(&mut Metrics)>(f: F) {
+    match REGISTRY.lock() {
+        Ok(mut m) => f(&mut m),
+        Err(_) => error!("metrics registry poisoned"),
+    }
+}
+
+/// Render all metrics in Prometheus text format.
+pub(crate) fn render(status: &AgentStatus) -> String {
+    let mut out = String::new();
+    let m = match REGISTRY.lock() {
+        Ok(m) => m,
+        Err(_) => return out,
+    };
+
+    header(&mut out, "zincati_state", "gauge", "Current agent state.");
+    for state in &UpdateAgentState::LABELS {
+        let value = if *state == status.state { 1 } else { 0 };
+        let _ = writeln!(out, "zincati_state{{state=\"{}\"}} {}", state, value);
+    }
+
+    header(
+        &mut out,
+        "zincati_paused",
+        "gauge",
+        "Whether updates are paused.",
+    );
+    let _ = writeln!(out, "zincati_paused {}", if status.paused { 1 } else { 0 });
+
+    if let Some(ref version) = status.booted_version {
+        header(
+            &mut out,
+            "zincati_booted_info",
+            "gauge",
+            "Booted OS version.",
+        );
+        let _ = writeln!(
+            out,
+            "zincati_booted_info{{version=\"{}\"}} 1",
+            escape(version)
+        );
+    }
+
+    header(
+        &mut out,
+        "zincati_update_available_info",
+        "gauge",
+        "Update target, if any.",
+    );
+    if let Some(ref version) = status.target_version {
+        let _ = writeln!(
+            out,
+            "zincati_update_available_info{{version=\"{}\",state=\"{}\"}} 1",
+            escape(version),
+            status.state
+        );
+    }
+
+    let timestamps = [
+        (
+            "zincati_last_check_timestamp_seconds",
+            "Time of the last successful update check.",
+            status.last_check_at,
+        ),
+        (
+            "zincati_last_stage_timestamp_seconds",
+            "Time of the last successful staging.",
+            m.last_stage,
+        ),
+        (
+            "zincati_last_finalize_timestamp_seconds",
+            "Time of the last successful finalization.",
+            m.last_finalize,
+        ),
+    ];
+    for (name, help, value) in &timestamps {
+        if let Some(t) = value {
+            header(&mut out, name, "gauge", help);
+            let _ = writeln!(out, "{} {}", name, t);
+        }
+    }
+
+    header(
+        &mut out,
+        "zincati_graph_fetches_total",
+        "counter",
+        "Graph requests to Cincinnati.",
+    );
+    let _ = writeln!(out, "zincati_graph_fetches_total {}", m.graph_fetches);
+
+    header(
+        &mut out,
+        "zincati_errors_total",
+        "counter",
+        "Errors, per subsystem.",
+    );
+    for subsystem in &Subsystem::ALL {
+        let count = m.errors.get(subsystem).cloned().unwrap_or(0);
+        let _ = writeln!(
+            out,
+            "zincati_errors_total{{subsystem=\"{}\"}} {}",
+            subsystem, count
+        );
+    }
+
+    header(
+        &mut out,
+        "zincati_lock_denials_total",
+        "counter",
+        "Finalizations denied by the lock manager.",
+    );
+    let _ = writeln!(out, "zincati_lock_denials_total {}", m.lock_denials);
+
+    out
+}
+
+/// Atomically write rendered metrics to a textfile.
+pub(crate) fn write_textfile(path: &Path, status: &AgentStatus) -> Fallible<()> {
+    // The collector only reads `*.prom` files, so the temporary one is ignored.
+    let tmp_path = path.with_extension("prom.tmp");
+    fs::write(&tmp_path, render(status))
+        .context(format!("failed to write '{}'", tmp_path.display()))?;
+    fs::rename(&tmp_path, path).context(format!("failed to write '{}'", path.display()))?;
+    Ok(())
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escape a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn epoch_secs() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::release;
+
+    fn status(state: &'static str) -> AgentStatus {
+        AgentStatus {
+            state,
+            target_version: Some("30.2".to_string()),
+            booted_version: Some("30.1 \"custom\"\\\n".to_string()),
+            last_check_at: Some(1_580_000_000),
+            in_flight: None,
+            reboot_pending_secs: None,
+            last_update: None,
+            retry: None,
+            abandon_reason: None,
+            paused: false,
+        }
+    }
+
+    /// Value of the metric sample on the given line.
+    fn sample(out: &str, line: &str) -> Option<u64> {
+        out.lines()
+            .find(|l| l.starts_with(line) && l[line.len()..].starts_with(' '))
+            .map(|l| l[line.len() + 1..].parse().unwrap())
+    }
+
+    #[test]
+    fn test_state_labels() {
+        let r = release("30.2", 'b');
+        let t = time::SystemTime::now();
+        let states = vec![
+            UpdateAgentState::StartState,
+            UpdateAgentState::Initialization,
+            UpdateAgentState::ReportedSteady,
+            UpdateAgentState::NoNewUpdate,
+            UpdateAgentState::StagingFailed(r.clone(), 1),
+            UpdateAgentState::UpdateStaged(r.clone()),
+            UpdateAgentState::FinalizationFailed(r.clone(), 1),
+            UpdateAgentState::RebootPending(r.clone(), t, 0),
+            UpdateAgentState::HealthCheck(r.clone(), t),
+            UpdateAgentState::RollbackPending(r.clone(), t),
+            UpdateAgentState::Abandoned(r, "bad release".to_string()),
+        ];
+        let labels: Vec<_> = states.iter().map(UpdateAgentState::label).collect();
+        assert_eq!(labels, UpdateAgentState::LABELS.to_vec());
+    }
+
+    #[test]
+    fn test_render() {
+        record_error(Subsystem::Strategy);
+        let out = render(&status("update-staged"));
+
+        for state in &UpdateAgentState::LABELS {
+            let line = format!("zincati_state{{state=\"{}\"}}", state);
+            let expected = if *state == "update-staged" { 1 } else { 0 };
+            assert_eq!(sample(&out, &line), Some(expected), "{}", state);
+        }
+
+        // Other tests may record errors concurrently.
+        for subsystem in &Subsystem::ALL {
+            let line = format!("zincati_errors_total{{subsystem=\"{}\"}}", subsystem);
+            assert!(sample(&out, &line).is_some(), "{}", subsystem);
+        }
+        let strategy = sample(&out, "zincati_errors_total{subsystem=\"strategy\"}");
+        assert!(strategy.unwrap() >= 1);
+
+        let booted = r#"zincati_booted_info{version="30.1 \"custom\"\\\n"}"#;
+        assert_eq!(sample(&out, booted), Some(1));
+        let target = r#"zincati_update_available_info{version="30.2",state="update-staged"}"#;
+        assert_eq!(sample(&out, target), Some(1));
+        assert_eq!(
+            sample(&out, "zincati_last_check_timestamp_seconds"),
+            Some(1_580_000_000)
+        );
+        assert_eq!(sample(&out, "zincati_paused"), Some(0));
+    }
+}
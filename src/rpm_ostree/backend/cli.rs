@@ -0,0 +1,250 @@
+//! rpm-ostree backend via its command-line interface.
+//!
+//! This is a fallback for systems where the DBus API is not usable.
+//! Transaction progress is not available in this mode.
+
+use super::OsBackend;
+use crate::rpm_ostree::deployment::{Deployment, Payload};
+use crate::rpm_ostree::progress::TransactionProgress;
+use actix::Recipient;
+use failure::{Fallible, ResultExt};
+use std::process::Command;
+
+/// rpm-ostree binary.
+static RPMOSTREE_BIN: &str = "rpm-ostree";
+
+/// rpm-ostree backend, running the `rpm-ostree` command.
+#[derive(Clone, Debug)]
+pub(crate) struct CliBackend {}
+
+/// Output of `rpm-ostree status --json`.
+#[derive(Debug, Deserialize)]
+struct CliStatus {
+    deployments: Vec<CliDeployment>,
+}
+
+/// Deployment, as reported by `rpm-ostree status --json`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct CliDeployment {
+    #[serde(default)]
+    id: String,
+    #[serde(default)]
+    version: String,
+    checksum: String,
+    base_checksum: Option<String>,
+    container_image_reference_digest: Option<String>,
+    #[serde(default)]
+    origin: String,
+    #[serde(default)]
+    timestamp: u64,
+    #[serde(default)]
+    booted: bool,
+    #[serde(default)]
+    staged: bool,
+    #[serde(default)]
+    pinned: bool,
+    #[serde(default)]
+    packages: Vec<String>,
+    #[serde(default)]
+    finalization_locked: bool,
+}
+
+impl From<CliDeployment> for Deployment {
+    fn from(d: CliDeployment) -> Self {
+        Self {
+            id: d.id,
+            version: d.version,
+            checksum: d.checksum,
+            base_checksum: d.base_checksum,
+            container_digest: d.container_image_reference_digest,
+            origin: d.origin,
+            timestamp: d.timestamp,
+            pinned: d.pinned,
+            layered_packages: d.packages,
+            booted: d.booted,
+            staged: d.staged,
+            finalization_locked: d.finalization_locked,
+        }
+    }
+}
+
+impl OsBackend for CliBackend {
+    fn stage(
+        &mut self,
+        release: &libcincinnati::Release,
+        refspec: Option<&str>,
+        _progress: Option<&Recipient<TransactionProgress>>,
+    ) -> Fallible<()> {
+        let checksum = match Payload::try_from_release(release)? {
+            Payload::OstreeCommit(c) => c,
+            p => bail!("unsupported payload for rpm-ostree deployment: {:?}", p),
+        };
+        let mut cmd = Command::new(RPMOSTREE_BIN);
+        cmd.args(stage_args(&checksum, refspec));
+        run(cmd).context("failed to stage deployment")?;
+        Ok(())
+    }
+
+    fn finalize(
+        &mut self,
+        release: &libcincinnati::Release,
+        _progress: Option<&Recipient<TransactionProgress>>,
+    ) -> Fallible<()> {
+        let checksum = match Payload::try_from_release(release)? {
+            Payload::OstreeCommit(c) => c,
+            p => bail!("unsupported payload for rpm-ostree deployment: {:?}", p),
+        };
+        let mut cmd = Command::new(RPMOSTREE_BIN);
+        cmd.args(finalize_args(&checksum));
+        run(cmd).context("failed to finalize deployment")?;
+        Ok(())
+    }
+
+    fn deployments(&mut self) -> Fallible<Vec<Deployment>> {
+        let mut cmd = Command::new(RPMOSTREE_BIN);
+        cmd.args(STATUS_ARGS);
+        let out = run(cmd).context("failed to query deployments")?;
+        parse_status(&out)
+    }
+
+    fn cleanup(&mut self) -> Fallible<()> {
+        let mut cmd = Command::new(RPMOSTREE_BIN);
+        cmd.args(CLEANUP_ARGS);
+        run(cmd).context("failed to clean up deployments")?;
+        Ok(())
+    }
+
+    fn rollback(&mut self, reboot: bool) -> Fallible<()> {
+        let mut cmd = Command::new(RPMOSTREE_BIN);
+        cmd.args(rollback_args(reboot));
+        run(cmd).context("failed to roll back")?;
+        Ok(())
+    }
+}
+
+/// Arguments to query deployments.
+static STATUS_ARGS: &[&str] = &["status", "--json"];
+
+/// Arguments to clean up pending deployments.
+static CLEANUP_ARGS: &[&str] = &["cleanup", "--pending"];
+
+/// Arguments to stage a locked deployment, rebasing if a refspec is given.
+fn stage_args<'a>(checksum: &'a str, refspec: Option<&'a str>) -> Vec<&'a str> {
+    match refspec {
+        Some(r) => vec!["rebase", "--lock-finalization", r, checksum],
+        None => vec!["deploy", "--lock-finalization", checksum],
+    }
+}
+
+/// Arguments to finalize a staged deployment.
+fn finalize_args(checksum: &str) -> Vec<&str> {
+    vec!["finalize-deployment", checksum]
+}
+
+/// Arguments to roll back to the previous deployment.
+fn rollback_args(reboot: bool) -> Vec<&'static str> {
+    let mut args = vec!["rollback"];
+    if reboot {
+        args.push("--reboot");
+    }
+    args
+}
+
+/// Parse the output of `rpm-ostree status --json`.
+fn parse_status(out: &[u8]) -> Fallible<Vec<Deployment>> {
+    let status: CliStatus =
+        serde_json::from_slice(out).context("failed to parse rpm-ostree status")?;
+    Ok(status
+        .deployments
+        .into_iter()
+        .map(Deployment::from)
+        .collect())
+}
+
+/// Run a command to completion, returning its standard output.
+fn run(mut cmd: Command) -> Fallible<Vec<u8>> {
+    trace!("running {:?}", cmd);
+    let out = cmd.output().context(format!("failed to run {:?}", cmd))?;
+    if !out.status.success() {
+        bail!(
+            "{:?} failed ({}): {}",
+            cmd,
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+    Ok(out.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `rpm-ostree status --json` output, with a staged deployment.
+    static STATUS_FIXTURE: &str = include_str!("../../../tests/fixtures/rpm-ostree-status.json");
+
+    #[test]
+    fn test_parse_status() {
+        let deployments = parse_status(STATUS_FIXTURE.as_bytes()).unwrap();
+        assert_eq!(deployments.len(), 3);
+
+        let staged = &deployments[0];
+        assert!(staged.staged && !staged.booted);
+        assert!(staged.finalization_locked);
+        assert_eq!(staged.version, "31.20200127.3.0");
+        assert_eq!(
+            staged.checksum,
+            "f25d2ba06c7c2b0b7d5a0f23b9f9e2c7a1a0a5bdbf2a6a2bd61f0d88a6c2a3a7"
+        );
+        assert_eq!(staged.origin, "fedora:fedora/x86_64/coreos/stable");
+        assert_eq!(staged.timestamp, 1_580_145_284);
+
+        let booted = &deployments[1];
+        assert!(booted.booted && !booted.staged && !booted.finalization_locked);
+        assert_eq!(booted.version, "31.20200113.3.1");
+        assert!(booted.id.starts_with("fedora-coreos-3b6c6a1d"));
+        assert_eq!(booted.layered_packages, vec!["htop".to_string()]);
+        assert_eq!(
+            booted.base_checksum.as_deref(),
+            Some("4d2b1c0b4b3f3d3c33f5e2d5f1e6c6a0a1e3a0b3f4e5d6c7b8a9f0e1d2c3b4a5")
+        );
+
+        let rollback = &deployments[2];
+        assert!(rollback.pinned && !rollback.booted && !rollback.staged);
+        assert_eq!(rollback.base_checksum, None);
+        assert!(rollback.layered_packages.is_empty());
+        assert_eq!(rollback.container_digest, None);
+    }
+
+    #[test]
+    fn test_parse_status_invalid() {
+        parse_status(b"").unwrap_err();
+        parse_status(b"{}").unwrap_err();
+        // Deployments without a checksum are rejected.
+        parse_status(br#"{"deployments": [{"version": "31.20200113.3.1"}]}"#).unwrap_err();
+        assert!(parse_status(br#"{"deployments": []}"#).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_args() {
+        assert_eq!(STATUS_ARGS, ["status", "--json"]);
+        assert_eq!(CLEANUP_ARGS, ["cleanup", "--pending"]);
+        assert_eq!(
+            stage_args("abc", None),
+            vec!["deploy", "--lock-finalization", "abc"]
+        );
+        assert_eq!(
+            stage_args("abc", Some("fedora:fedora/x86_64/coreos/next")),
+            vec![
+                "rebase",
+                "--lock-finalization",
+                "fedora:fedora/x86_64/coreos/next",
+                "abc"
+            ]
+        );
+        assert_eq!(finalize_args("abc"), vec!["finalize-deployment", "abc"]);
+        assert_eq!(rollback_args(false), vec!["rollback"]);
+        assert_eq!(rollback_args(true), vec!["rollback", "--reboot"]);
+    }
+}
@@ -0,0 +1,409 @@
+//! rpm-ostree backend over DBus.
+
+use super::OsBackend;
+use crate::rpm_ostree::deployment::{DbusDeployment, Deployment, Payload};
+use crate::rpm_ostree::progress::TransactionProgress;
+use actix::Recipient;
+use dbus::arg::{RefArg, Variant};
+use dbus::stdintf::org_freedesktop_dbus::Properties;
+use dbus::{BusType, Connection, ConnectionItem};
+use failure::{Fail, Fallible, ResultExt};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// rpm-ostree DBus service name.
+static RPMOSTREE_BUS_NAME: &str = "org.projectatomic.rpmostree1";
+
+/// rpm-ostree DBus path to the Sysroot object.
+static SYSROOT_PATH: &str = "/org/projectatomic/rpmostree1/Sysroot";
+
+/// rpm-ostree DBus interface for the Sysroot object.
+static SYSROOT_INTERFACE: &str = "org.projectatomic.rpmostree1.Sysroot";
+
+/// rpm-ostree DBus interface for OS objects.
+static OS_INTERFACE: &str = "org.projectatomic.rpmostree1.OS";
+
+/// rpm-ostree DBus interface for transactions.
+static TRANSACTION_INTERFACE: &str = "org.projectatomic.rpmostree1.Transaction";
+
+/// Standard DBus properties interface.
+static PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+
+/// Timeout for DBus method calls, in milliseconds.
+static DBUS_TIMEOUT_MS: i32 = 5000;
+
+/// Polling interval for transaction signals, in milliseconds.
+static TRANSACTION_POLL_MS: u32 = 1000;
+
+/// Initial delay before reconnecting to DBus, in seconds.
+static RECONNECT_BASE_SECS: u64 = 1;
+
+/// Maximum delay before reconnecting to DBus, in seconds.
+static RECONNECT_MAX_SECS: u64 = 300;
+
+/// DBus errors which signal a broken connection.
+static DISCONNECT_ERRORS: &[&str] = &[
+    "org.freedesktop.DBus.Error.Disconnected",
+    "org.freedesktop.DBus.Error.NoServer",
+];
+
+/// Options dictionary (`a{sv}`) for rpm-ostree methods.
+type DbusOptions = HashMap<&'static str, Variant<Box<dyn RefArg>>>;
+
+/// DBus error, as returned by the bus or by rpm-ostree.
+#[derive(Debug)]
+pub(crate) struct DbusError {
+    pub(crate) name: String,
+    pub(crate) message: String,
+}
+
+impl fmt::Display for DbusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DBus error {}: {}", self.name, self.message)
+    }
+}
+
+impl Fail for DbusError {}
+
+/// rpm-ostree backend, talking to the daemon over DBus.
+#[derive(Debug, Default)]
+pub(crate) struct DbusBackend {
+    /// Custom bus address, instead of the system bus.
+    bus_address: Option<String>,
+    /// Maximum time without progress before a transaction is cancelled.
+    stall_timeout: Duration,
+    conn: Option<Connection>,
+    /// Consecutive failed connection attempts.
+    failures: u32,
+    /// Earliest time for the next connection attempt.
+    not_before: Option<Instant>,
+}
+
+impl DbusBackend {
+    pub(crate) fn new(bus_address: Option<String>, stall_timeout: Duration) -> Self {
+        Self {
+            bus_address,
+            stall_timeout,
+            ..Self::default()
+        }
+    }
+
+    /// Open a connection to the configured bus.
+    fn connect(&self) -> Fallible<Connection> {
+        let conn = match self.bus_address {
+            Some(ref addr) => {
+                let conn = Connection::open_private(addr).map_err(dbus_error)?;
+                conn.register().map_err(dbus_error)?;
+                conn
+            }
+            None => Connection::get_private(BusType::System).map_err(dbus_error)?,
+        };
+        Ok(conn)
+    }
+
+    /// Return the current connection, reconnecting if needed.
+    ///
+    /// Failed attempts are retried with exponential backoff.
+    fn conn(&mut self) -> Fallible<&Connection> {
+        if self.conn.is_none() {
+            if let Some(not_before) = self.not_before {
+                let now = Instant::now();
+                if now < not_before {
+                    bail!(
+                        "not connected to DBus, next attempt in {:?}",
+                        not_before - now
+                    );
+                }
+            }
+
+            match self.connect() {
+                Ok(c) => {
+                    if self.failures > 0 {
+                        info!("reconnected to DBus");
+                    }
+                    self.failures = 0;
+                    self.not_before = None;
+                    self.conn = Some(c);
+                }
+                Err(e) => {
+                    self.failures = self.failures.saturating_add(1);
+                    let exp = self.failures.saturating_sub(1).min(16);
+                    let secs = (RECONNECT_BASE_SECS << exp).min(RECONNECT_MAX_SECS);
+                    self.not_before = Some(Instant::now() + Duration::from_secs(secs));
+                    return Err(e.context("failed to connect to DBus").into());
+                }
+            }
+        }
+
+        self.conn
+            .as_ref()
+            .ok_or_else(|| format_err!("not connected to DBus"))
+    }
+
+    /// Drop the current connection if the result signals that it broke.
+    fn check_connection<T>(&mut self, res: &Fallible<T>) {
+        let broken = match res {
+            Err(e) => e.iter_chain().any(|cause| {
+                cause
+                    .downcast_ref::<DbusError>()
+                    .map(|err| DISCONNECT_ERRORS.contains(&err.name.as_str()))
+                    .unwrap_or(false)
+            }),
+            Ok(_) => false,
+        };
+        if broken {
+            warn!("DBus connection lost, reconnecting on next request");
+            self.conn = None;
+        }
+    }
+
+    /// Run an operation on the current connection, tracking its health.
+    fn with_conn<T, F>(&mut self, f: F) -> Fallible<T>
+    where
+        F: FnOnce(&Connection, Duration) -> Fallible<T>,
+    {
+        let stall_timeout = self.stall_timeout;
+        let res = self.conn().and_then(|conn| f(conn, stall_timeout));
+        self.check_connection(&res);
+        res
+    }
+}
+
+impl OsBackend for DbusBackend {
+    fn stage(
+        &mut self,
+        release: &libcincinnati::Release,
+        refspec: Option<&str>,
+        progress: Option<&Recipient<TransactionProgress>>,
+    ) -> Fallible<()> {
+        self.with_conn(|conn, stall| stage_deployment(conn, release, refspec, progress, stall))
+    }
+
+    fn finalize(
+        &mut self,
+        release: &libcincinnati::Release,
+        progress: Option<&Recipient<TransactionProgress>>,
+    ) -> Fallible<()> {
+        self.with_conn(|conn, stall| finalize_deployment(conn, release, progress, stall))
+    }
+
+    fn deployments(&mut self) -> Fallible<Vec<Deployment>> {
+        self.with_conn(|conn, _| query_deployments(conn))
+    }
+
+    fn cleanup(&mut self) -> Fallible<()> {
+        self.with_conn(cleanup_deployments)
+    }
+
+    fn rollback(&mut self, reboot: bool) -> Fallible<()> {
+        self.with_conn(|conn, stall| rollback_deployment(conn, reboot, stall))
+    }
+}
+
+/// Stage a deployment via `UpdateDeployment`, rebasing if requested.
+fn stage_deployment(
+    conn: &Connection,
+    release: &libcincinnati::Release,
+    refspec: Option<&str>,
+    progress: Option<&Recipient<TransactionProgress>>,
+    stall_timeout: Duration,
+) -> Fallible<()> {
+    let checksum = ostree_commit(release)?;
+
+    let mut modifiers: DbusOptions = HashMap::new();
+    modifiers.insert("set-revision", Variant(Box::new(checksum)));
+    if let Some(refspec) = refspec {
+        modifiers.insert("set-refspec", Variant(Box::new(refspec.to_string())));
+    }
+
+    // Keep the staged deployment locked, it is only finalized on request.
+    // Stream entry releases can be older than the booted one, so rebases
+    // may downgrade (as `rpm-ostree rebase` does).
+    let mut options: DbusOptions = HashMap::new();
+    options.insert("reboot", Variant(Box::new(false)));
+    options.insert("allow-downgrade", Variant(Box::new(refspec.is_some())));
+    options.insert("lock-finalization", Variant(Box::new(true)));
+
+    let os_path = booted_os_path(conn)?;
+    let call = method_call(&os_path, OS_INTERFACE, "UpdateDeployment")?.append2(modifiers, options);
+    let address = request_transaction(conn, call, "UpdateDeployment")?;
+    run_transaction(&address, progress, stall_timeout).context("failed to stage deployment")?;
+    Ok(())
+}
+
+/// Finalize the staged deployment via `FinalizeDeployment`.
+fn finalize_deployment(
+    conn: &Connection,
+    release: &libcincinnati::Release,
+    progress: Option<&Recipient<TransactionProgress>>,
+    stall_timeout: Duration,
+) -> Fallible<()> {
+    // rpm-ostree refuses to finalize if the staged deployment
+    // does not match the expected checksum.
+    let mut options: DbusOptions = HashMap::new();
+    options.insert("checksum", Variant(Box::new(ostree_commit(release)?)));
+
+    let os_path = booted_os_path(conn)?;
+    let call = method_call(&os_path, OS_INTERFACE, "FinalizeDeployment")?.append1(options);
+    let address = request_transaction(conn, call, "FinalizeDeployment")?;
+    run_transaction(&address, progress, stall_timeout).context("failed to finalize deployment")?;
+    Ok(())
+}
+
+/// Remove the pending (or staged) deployment via `Cleanup`.
+fn cleanup_deployments(conn: &Connection, stall_timeout: Duration) -> Fallible<()> {
+    let elements = vec!["pending-deploy"];
+    let os_path = booted_os_path(conn)?;
+    let call = method_call(&os_path, OS_INTERFACE, "Cleanup")?.append1(elements);
+    let address = request_transaction(conn, call, "Cleanup")?;
+    run_transaction(&address, None, stall_timeout).context("failed to clean up deployments")?;
+    Ok(())
+}
+
+/// Make the rollback deployment the default one, via `Rollback`.
+fn rollback_deployment(conn: &Connection, reboot: bool, stall_timeout: Duration) -> Fallible<()> {
+    let mut options: DbusOptions = HashMap::new();
+    options.insert("reboot", Variant(Box::new(reboot)));
+
+    let os_path = booted_os_path(conn)?;
+    let call = method_call(&os_path, OS_INTERFACE, "Rollback")?.append1(options);
+    let address = request_transaction(conn, call, "Rollback")?;
+    run_transaction(&address, None, stall_timeout).context("failed to roll back")?;
+    Ok(())
+}
+
+/// Read all deployments from the Sysroot object.
+fn query_deployments(conn: &Connection) -> Fallible<Vec<Deployment>> {
+    let sysroot = conn.with_path(RPMOSTREE_BUS_NAME, SYSROOT_PATH, DBUS_TIMEOUT_MS);
+    let deployments: Vec<DbusDeployment> = sysroot
+        .get(SYSROOT_INTERFACE, "Deployments")
+        .map_err(dbus_error)
+        .context("failed to read deployments")?;
+
+    deployments.iter().map(Deployment::try_from_dbus).collect()
+}
+
+/// Ostree commit of a release, the only payload rpm-ostree can deploy.
+fn ostree_commit(release: &libcincinnati::Release) -> Fallible<String> {
+    match Payload::try_from_release(release)? {
+        Payload::OstreeCommit(c) => Ok(c),
+        p => bail!("unsupported payload for rpm-ostree deployment: {:?}", p),
+    }
+}
+
+/// Send a transaction request and return the transaction address.
+fn request_transaction(conn: &Connection, call: dbus::Message, method: &str) -> Fallible<String> {
+    let reply = conn
+        .send_with_reply_and_block(call, DBUS_TIMEOUT_MS)
+        .map_err(dbus_error)
+        .context(format!("failed to request {}", method))?;
+    let address: String = reply
+        .read1()
+        .map_err(|e| format_err!("unexpected reply to {}: {}", method, e))?;
+    Ok(address)
+}
+
+/// Build a method call to an rpm-ostree object.
+fn method_call(path: &str, interface: &str, method: &str) -> Fallible<dbus::Message> {
+    dbus::Message::new_method_call(RPMOSTREE_BUS_NAME, path, interface, method)
+        .map_err(|e| format_err!("failed to build DBus call '{}': {}", method, e))
+}
+
+/// Get the object path of the booted OS.
+fn booted_os_path(conn: &Connection) -> Fallible<String> {
+    let call = method_call(SYSROOT_PATH, PROPERTIES_INTERFACE, "Get")?
+        .append2(SYSROOT_INTERFACE, "Booted");
+    let reply = conn
+        .send_with_reply_and_block(call, DBUS_TIMEOUT_MS)
+        .map_err(dbus_error)
+        .context("failed to get booted OS")?;
+    let booted: Variant<dbus::Path> = reply
+        .read1()
+        .map_err(|e| format_err!("unexpected booted OS value: {}", e))?;
+    Ok(booted.0.to_string())
+}
+
+/// Start an rpm-ostree transaction and wait for it to finish.
+///
+/// Transactions are served on a dedicated peer-to-peer connection.
+/// Progress signals are forwarded to `progress`, and the transaction is
+/// cancelled if no signal is received within `stall_timeout`.
+fn run_transaction(
+    address: &str,
+    progress: Option<&Recipient<TransactionProgress>>,
+    stall_timeout: Duration,
+) -> Fallible<()> {
+    trace!("rpm-ostree transaction at '{}'", address);
+    let conn = Connection::open_private(address)
+        .map_err(dbus_error)
+        .context("failed to connect to transaction")?;
+
+    let start = method_call("/", TRANSACTION_INTERFACE, "Start")?;
+    let reply = conn
+        .send_with_reply_and_block(start, DBUS_TIMEOUT_MS)
+        .map_err(dbus_error)
+        .context("failed to start transaction")?;
+    let started: bool = reply
+        .read1()
+        .map_err(|e| format_err!("unexpected reply to Start: {}", e))?;
+    if !started {
+        bail!("transaction already started by another client");
+    }
+
+    let notify = |event: TransactionProgress| {
+        if let Some(recipient) = progress {
+            if let Err(e) = recipient.do_send(event) {
+                debug!("failed to forward transaction progress: {}", e);
+            }
+        }
+    };
+
+    let mut last_activity = Instant::now();
+    for item in conn.iter(TRANSACTION_POLL_MS as i32) {
+        let event = match item {
+            ConnectionItem::Signal(ref s) => TransactionProgress::try_from_signal(s),
+            _ => None,
+        };
+
+        let event = match event {
+            Some(ev) => ev,
+            None => {
+                let idle = last_activity.elapsed();
+                if idle > stall_timeout {
+                    notify(TransactionProgress::Stalled {
+                        idle_secs: idle.as_secs(),
+                    });
+                    if let Ok(cancel) = method_call("/", TRANSACTION_INTERFACE, "Cancel") {
+                        let _ = conn.send_with_reply_and_block(cancel, DBUS_TIMEOUT_MS);
+                    }
+                    bail!("transaction stalled, no progress for {:?}", idle);
+                }
+                continue;
+            }
+        };
+        last_activity = Instant::now();
+        trace!("rpm-ostree transaction: {}", event);
+
+        if let TransactionProgress::Finished { success, ref error } = event {
+            let error = error.clone();
+            notify(event);
+            if !success {
+                bail!("transaction failed: {}", error);
+            }
+            return Ok(());
+        }
+        notify(event);
+    }
+
+    bail!("transaction connection closed before completion")
+}
+
+/// Map a DBus error into a failure.
+fn dbus_error(err: dbus::Error) -> failure::Error {
+    DbusError {
+        name: err.name().unwrap_or("(unknown)").to_string(),
+        message: err.message().unwrap_or("(no message)").to_string(),
+    }
+    .into()
+}
@@ -0,0 +1,350 @@
+//! Mock backend, simulating an ostree system in memory.
+//!
+//! It records all calls and can be configured to fail selected operations,
+//! for exercising update strategies without a real ostree system.
+//! Finalization simulates the reboot, booting into the staged deployment.
+
+use super::OsBackend;
+use crate::config::MockConfig;
+use crate::rpm_ostree::deployment::{Deployment, Payload};
+use crate::rpm_ostree::progress::TransactionProgress;
+use actix::Recipient;
+use failure::Fallible;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+/// Operations which can be configured to fail.
+static MOCK_OPERATIONS: &[&str] = &["stage", "finalize", "status", "cleanup", "rollback"];
+
+/// Mock backend; clones share the same simulated system.
+#[derive(Clone, Debug)]
+pub(crate) struct MockBackend {
+    state: Arc<Mutex<MockState>>,
+    /// Operations which always fail.
+    failures: Arc<HashSet<String>>,
+}
+
+/// Simulated system state.
+#[derive(Debug)]
+struct MockState {
+    /// Deployments, next boot first.
+    deployments: Vec<Deployment>,
+    /// Calls received so far.
+    calls: Vec<MockCall>,
+}
+
+/// A call received by the mock backend.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct MockCall {
+    /// Operation name, as in `MOCK_OPERATIONS`.
+    pub(crate) operation: String,
+    /// Operation argument (e.g. release version), if any.
+    pub(crate) detail: String,
+}
+
+impl MockBackend {
+    pub(crate) fn try_from_config(cfg: &MockConfig) -> Fallible<Self> {
+        if let Some(op) = cfg
+            .fail
+            .iter()
+            .find(|op| !MOCK_OPERATIONS.contains(&op.as_str()))
+        {
+            bail!("unknown mock operation '{}'", op);
+        }
+
+        let booted = Deployment {
+            id: "mock-0".to_string(),
+            version: cfg.booted_version.clone(),
+            checksum: cfg.booted_checksum.clone(),
+            base_checksum: None,
+            container_digest: None,
+            origin: cfg.booted_origin.clone(),
+            timestamp: now_secs(),
+            pinned: false,
+            layered_packages: vec![],
+            booted: true,
+            staged: false,
+            finalization_locked: false,
+        };
+        let state = MockState {
+            deployments: vec![booted],
+            calls: vec![],
+        };
+        let mock = Self {
+            state: Arc::new(Mutex::new(state)),
+            failures: Arc::new(cfg.fail.iter().cloned().collect()),
+        };
+        Ok(mock)
+    }
+
+    /// Calls received so far, oldest first.
+    #[cfg(test)]
+    pub(crate) fn calls(&self) -> Vec<MockCall> {
+        self.state
+            .lock()
+            .expect("mock state poisoned")
+            .calls
+            .clone()
+    }
+
+    /// Record a call, and return the simulated system if it should proceed.
+    fn call(
+        &self,
+        operation: &str,
+        detail: &str,
+    ) -> Fallible<std::sync::MutexGuard<'_, MockState>> {
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|_| format_err!("mock state poisoned"))?;
+        info!("mock backend: {} {}", operation, detail);
+        state.calls.push(MockCall {
+            operation: operation.to_string(),
+            detail: detail.to_string(),
+        });
+        trace!("mock backend calls: {:?}", state.calls);
+
+        if self.failures.contains(operation) {
+            bail!("simulated '{}' failure", operation);
+        }
+        Ok(state)
+    }
+}
+
+impl OsBackend for MockBackend {
+    fn stage(
+        &mut self,
+        release: &libcincinnati::Release,
+        refspec: Option<&str>,
+        _progress: Option<&Recipient<TransactionProgress>>,
+    ) -> Fallible<()> {
+        let payload = Payload::try_from_release(release)?;
+        let mut state = self.call("stage", release.version())?;
+
+        let booted_origin = state
+            .deployments
+            .iter()
+            .find(|d| d.booted)
+            .map(|d| d.origin.clone())
+            .unwrap_or_default();
+        let (checksum, container_digest) = match payload {
+            Payload::OstreeCommit(c) => (c, None),
+            Payload::ContainerDigest(d) => (d.clone(), Some(d)),
+        };
+        let staged = Deployment {
+            id: format!("mock-{}", state.calls.len()),
+            version: release.version().to_string(),
+            checksum,
+            base_checksum: None,
+            container_digest,
+            origin: refspec.map(String::from).unwrap_or(booted_origin),
+            timestamp: now_secs(),
+            pinned: false,
+            layered_packages: vec![],
+            booted: false,
+            staged: true,
+            finalization_locked: true,
+        };
+        state.deployments.retain(|d| !d.staged);
+        state.deployments.insert(0, staged);
+        Ok(())
+    }
+
+    fn finalize(
+        &mut self,
+        release: &libcincinnati::Release,
+        _progress: Option<&Recipient<TransactionProgress>>,
+    ) -> Fallible<()> {
+        let payload = Payload::try_from_release(release)?;
+        let mut state = self.call("finalize", release.version())?;
+
+        let staged = match state.deployments.iter().find(|d| d.staged) {
+            Some(d) => d,
+            None => bail!("no staged deployment to finalize"),
+        };
+        if !payload.matches(staged) {
+            bail!(
+                "staged deployment '{}' does not match release",
+                staged.checksum
+            );
+        }
+        info!(
+            "mock backend: simulating reboot into '{}'",
+            release.version()
+        );
+        // The staged deployment is first, the previous booted one
+        // is kept as the rollback deployment.
+        for d in state.deployments.iter_mut() {
+            d.booted = d.staged;
+            d.staged = false;
+            d.finalization_locked = false;
+        }
+        Ok(())
+    }
+
+    fn deployments(&mut self) -> Fallible<Vec<Deployment>> {
+        let state = self.call("status", "")?;
+        Ok(state.deployments.clone())
+    }
+
+    fn cleanup(&mut self) -> Fallible<()> {
+        let mut state = self.call("cleanup", "")?;
+        let pos = state.deployments.iter().position(|d| d.booted).unwrap_or(0);
+        state.deployments.drain(..pos);
+        Ok(())
+    }
+
+    fn rollback(&mut self, reboot: bool) -> Fallible<()> {
+        let mut state = self.call("rollback", if reboot { "reboot" } else { "" })?;
+        let pos = match state.deployments.iter().position(|d| d.booted) {
+            Some(p) => p,
+            None => bail!("no booted deployment"),
+        };
+        if pos + 1 >= state.deployments.len() {
+            bail!("no rollback deployment");
+        }
+        let rollback = state.deployments.remove(pos + 1);
+        state.deployments.retain(|d| !d.staged);
+        state.deployments.insert(0, rollback);
+        if reboot {
+            info!("mock backend: simulating reboot into rollback deployment");
+            for (i, d) in state.deployments.iter_mut().enumerate() {
+                d.booted = i == 0;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn now_secs() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::release;
+
+    fn mock_config(fail: &[&str]) -> MockConfig {
+        MockConfig {
+            booted_version: "30.1".to_string(),
+            booted_checksum: "a".repeat(64),
+            booted_origin: "fedora:fedora/x86_64/coreos/testing".to_string(),
+            fail: fail.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    fn call(operation: &str, detail: &str) -> MockCall {
+        MockCall {
+            operation: operation.to_string(),
+            detail: detail.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_stage_finalize_reboot() {
+        let mock = MockBackend::try_from_config(&mock_config(&[])).unwrap();
+        let mut backend = mock.clone();
+        let next = release("30.2", 'b');
+
+        backend.stage(&next, None, None).unwrap();
+        let deployments = backend.deployments().unwrap();
+        assert_eq!(deployments.len(), 2);
+        let staged = &deployments[0];
+        assert!(staged.staged && staged.finalization_locked && !staged.booted);
+        assert!(staged.matches_release(&next));
+        assert_eq!(staged.origin, "fedora:fedora/x86_64/coreos/testing");
+
+        backend.finalize(&next, None).unwrap();
+        let deployments = backend.deployments().unwrap();
+        assert_eq!(deployments.len(), 2);
+        let booted = &deployments[0];
+        assert!(booted.booted && !booted.staged && !booted.finalization_locked);
+        assert!(booted.matches_release(&next));
+        let previous = &deployments[1];
+        assert!(!previous.booted);
+        assert_eq!(previous.version, "30.1");
+
+        // Calls are shared across clones.
+        let expected = vec![
+            call("stage", "30.2"),
+            call("status", ""),
+            call("finalize", "30.2"),
+            call("status", ""),
+        ];
+        assert_eq!(mock.calls(), expected);
+    }
+
+    #[test]
+    fn test_stage_rebase() {
+        let mut backend = MockBackend::try_from_config(&mock_config(&[])).unwrap();
+        let next = release("30.0", 'b');
+        let refspec = "fedora:fedora/x86_64/coreos/stable";
+
+        backend.stage(&next, Some(refspec), None).unwrap();
+        let deployments = backend.deployments().unwrap();
+        assert_eq!(deployments[0].origin, refspec);
+        assert_eq!(deployments[0].stream(), Some("stable"));
+    }
+
+    #[test]
+    fn test_finalize_mismatch() {
+        let mut backend = MockBackend::try_from_config(&mock_config(&[])).unwrap();
+        let next = release("30.2", 'b');
+
+        backend.finalize(&next, None).unwrap_err();
+        backend.stage(&next, None, None).unwrap();
+        backend.finalize(&release("30.3", 'c'), None).unwrap_err();
+
+        // Nothing was booted.
+        let deployments = backend.deployments().unwrap();
+        assert!(deployments[0].staged);
+        assert!(deployments[1].booted);
+    }
+
+    #[test]
+    fn test_rollback() {
+        let mut backend = MockBackend::try_from_config(&mock_config(&[])).unwrap();
+        backend.rollback(false).unwrap_err();
+
+        let next = release("30.2", 'b');
+        backend.stage(&next, None, None).unwrap();
+        backend.finalize(&next, None).unwrap();
+
+        // Without a reboot, the rollback deployment is only pending.
+        backend.rollback(false).unwrap();
+        let deployments = backend.deployments().unwrap();
+        assert_eq!(deployments[0].version, "30.1");
+        assert!(!deployments[0].booted);
+        assert!(deployments[1].booted);
+
+        // With a reboot, the rollback deployment is booted.
+        let mut backend = MockBackend::try_from_config(&mock_config(&[])).unwrap();
+        backend.stage(&next, None, None).unwrap();
+        backend.finalize(&next, None).unwrap();
+        backend.rollback(true).unwrap();
+        let deployments = backend.deployments().unwrap();
+        assert_eq!(deployments.len(), 2);
+        assert!(deployments[0].booted);
+        assert_eq!(deployments[0].version, "30.1");
+        assert!(!deployments[1].booted);
+        assert_eq!(deployments[1].version, "30.2");
+    }
+
+    #[test]
+    fn test_simulated_failures() {
+        MockBackend::try_from_config(&mock_config(&["unknown"])).unwrap_err();
+
+        let mock = MockBackend::try_from_config(&mock_config(&["finalize"])).unwrap();
+        let mut backend = mock.clone();
+        let next = release("30.2", 'b');
+        backend.stage(&next, None, None).unwrap();
+        backend.finalize(&next, None).unwrap_err();
+        assert!(backend.deployments().unwrap()[0].staged);
+        assert_eq!(mock.calls()[1], call("finalize", "30.2"));
+    }
+}
@@ -0,0 +1,78 @@
+//! OS-update backends.
+//!
+//! Backends perform blocking operations, and are driven by the
+//! `BlockingClient` sync actor.
+
+mod cli;
+mod dbus;
+mod mock;
+
+pub(crate) use self::cli::CliBackend;
+pub(crate) use self::dbus::DbusBackend;
+pub(crate) use self::mock::MockBackend;
+
+use super::deployment::Deployment;
+use super::progress::TransactionProgress;
+use crate::config::{RpmOstreeBackend, RpmOstreeConfig};
+use actix::Recipient;
+use failure::Fallible;
+
+/// Operations for staging and applying OS updates.
+pub(crate) trait OsBackend: std::fmt::Debug {
+    /// Stage the release payload, rebasing to `refspec` if any.
+    ///
+    /// The staged deployment must stay locked until finalized.
+    fn stage(
+        &mut self,
+        release: &libcincinnati::Release,
+        refspec: Option<&str>,
+        progress: Option<&Recipient<TransactionProgress>>,
+    ) -> Fallible<()>;
+
+    /// Unlock the staged deployment for the release and reboot into it.
+    fn finalize(
+        &mut self,
+        release: &libcincinnati::Release,
+        progress: Option<&Recipient<TransactionProgress>>,
+    ) -> Fallible<()>;
+
+    /// List all deployments, next boot first.
+    fn deployments(&mut self) -> Fallible<Vec<Deployment>>;
+
+    /// Remove the pending or staged deployment.
+    fn cleanup(&mut self) -> Fallible<()>;
+
+    /// Make the rollback deployment the default for next boot, optionally rebooting.
+    fn rollback(&mut self, reboot: bool) -> Fallible<()>;
+}
+
+/// Backend selection, with any state shared across instances.
+#[derive(Clone, Debug)]
+pub(crate) enum BackendKind {
+    Cli,
+    Dbus(RpmOstreeConfig),
+    Mock(MockBackend),
+}
+
+impl BackendKind {
+    /// Select a backend from configuration.
+    pub(crate) fn try_from_config(cfg: RpmOstreeConfig) -> Fallible<Self> {
+        let kind = match cfg.backend {
+            RpmOstreeBackend::Cli => BackendKind::Cli,
+            RpmOstreeBackend::Dbus => BackendKind::Dbus(cfg),
+            RpmOstreeBackend::Mock => BackendKind::Mock(MockBackend::try_from_config(&cfg.mock)?),
+        };
+        Ok(kind)
+    }
+
+    /// Create a backend instance.
+    pub(crate) fn instantiate(&self) -> Box<dyn OsBackend> {
+        match self {
+            BackendKind::Cli => Box::new(CliBackend {}),
+            BackendKind::Dbus(cfg) => {
+                Box::new(DbusBackend::new(cfg.bus_address.clone(), cfg.stall_timeout))
+            }
+            BackendKind::Mock(m) => Box::new(m.clone()),
+        }
+    }
+}
@@ -1,111 +1,120 @@
-//! Blocking DBus client for rpm-ostree.
+//! Blocking client for rpm-ostree, driving an OS-update backend.
 
+use super::backend::{BackendKind, OsBackend};
+use super::deployment::Deployment;
+use super::progress::TransactionProgress;
 use actix::prelude::*;
-use dbus::arg::Array;
-use dbus::{BusType, Connection};
 use failure::Fallible;
 
-/// DBus client, blocking implementation.
-#[derive(Debug, Default)]
-pub struct DbusClient {
-    conn: Option<Connection>,
+/// Blocking client, running backend operations on a sync arbiter.
+#[derive(Debug)]
+pub struct BlockingClient {
+    backend: Box<dyn OsBackend>,
 }
 
-impl Actor for DbusClient {
+impl BlockingClient {
+    pub(crate) fn new(kind: &BackendKind) -> Self {
+        Self {
+            backend: kind.instantiate(),
+        }
+    }
+}
+
+impl Actor for BlockingClient {
     type Context = SyncContext<Self>;
 
-    fn started(&mut self, ctx: &mut Self::Context) {
-        match Connection::get_private(BusType::Session) {
-            Ok(c) => {
-                self.conn = Some(c);
-            }
-            Err(e) => {
-                error!("failed to connect to DBus: {}", e);
-                ctx.terminate();
-            }
-        };
-        trace!("dbus client started");
+    fn started(&mut self, _ctx: &mut Self::Context) {
+        trace!("blocking rpm-ostree client started");
     }
 }
 
-/// DBus request: stage an rpm-ostree deployment.
+/// Blocking request: stage a deployment.
 pub(crate) struct StageDeployment {
     pub(crate) release: libcincinnati::Release,
+    /// Origin refspec to rebase onto, if switching streams.
+    pub(crate) refspec: Option<String>,
+    /// Recipient for transaction progress, if any.
+    pub(crate) progress: Option<Recipient<TransactionProgress>>,
 }
 
 impl Message for StageDeployment {
     type Result = Fallible<libcincinnati::Release>;
 }
 
-impl Handler<StageDeployment> for DbusClient {
+impl Handler<StageDeployment> for BlockingClient {
     type Result = Fallible<libcincinnati::Release>;
 
     fn handle(&mut self, msg: StageDeployment, _ctx: &mut Self::Context) -> Self::Result {
-        // TODO(lucab): implement real call to rpm-ostree.
-        // https://github.com/projectatomic/rpm-ostree/issues/1748
-        let call = dbus::Message::new_method_call(
-            "org.freedesktop.DBus",
-            "/",
-            "org.freedesktop.DBus",
-            "ListNames",
-        )
-        .map_err(|e| format_err!("{}", e))?;
-        let r = self
-            .conn
-            .as_ref()
-            .unwrap()
-            .send_with_reply_and_block(call, 2000)
-            .unwrap();
-        let arr: Array<&str, _> = r.get1().unwrap();
-        for name in arr {
-            if name.starts_with("org.") {
-                debug!("dbus result: {}", name);
-                break;
-            }
-        }
-
-        warn!("rpm-ostree stage: stubbed");
+        self.backend
+            .stage(&msg.release, msg.refspec.as_deref(), msg.progress.as_ref())?;
         Ok(msg.release)
     }
 }
 
-/// DBus request: stage an rpm-ostree deployment.
+/// Blocking request: finalize a staged deployment (and reboot).
 pub(crate) struct FinalizeDeployment {
     pub(crate) release: libcincinnati::Release,
+    /// Recipient for transaction progress, if any.
+    pub(crate) progress: Option<Recipient<TransactionProgress>>,
 }
 
 impl Message for FinalizeDeployment {
     type Result = Fallible<libcincinnati::Release>;
 }
 
-impl Handler<FinalizeDeployment> for DbusClient {
+impl Handler<FinalizeDeployment> for BlockingClient {
     type Result = Fallible<libcincinnati::Release>;
 
     fn handle(&mut self, msg: FinalizeDeployment, _ctx: &mut Self::Context) -> Self::Result {
-        // TODO(lucab): implement real call to rpm-ostree.
-        // https://github.com/projectatomic/rpm-ostree/issues/1748
-        let call = dbus::Message::new_method_call(
-            "org.freedesktop.DBus",
-            "/",
-            "org.freedesktop.DBus",
-            "ListNames",
-        )
-        .map_err(|e| format_err!("{}", e))?;
-        let r = self
-            .conn
-            .as_ref()
-            .unwrap()
-            .send_with_reply_and_block(call, 2000)
-            .unwrap();
-        let arr: Array<&str, _> = r.get1().unwrap();
-        for name in arr {
-            if name.starts_with("com.") {
-                debug!("dbus result: {}", name);
-                break;
-            }
-        }
-
-        warn!("rpm-ostree finalize: stubbed");
+        self.backend.finalize(&msg.release, msg.progress.as_ref())?;
         Ok(msg.release)
     }
 }
+
+/// Blocking request: query all deployments.
+pub(crate) struct QueryDeployments {}
+
+impl Message for QueryDeployments {
+    type Result = Fallible<Vec<Deployment>>;
+}
+
+impl Handler<QueryDeployments> for BlockingClient {
+    type Result = Fallible<Vec<Deployment>>;
+
+    fn handle(&mut self, _msg: QueryDeployments, _ctx: &mut Self::Context) -> Self::Result {
+        self.backend.deployments()
+    }
+}
+
+/// Blocking request: remove the pending or staged deployment.
+pub(crate) struct CleanupDeployments {}
+
+impl Message for CleanupDeployments {
+    type Result = Fallible<()>;
+}
+
+impl Handler<CleanupDeployments> for BlockingClient {
+    type Result = Fallible<()>;
+
+    fn handle(&mut self, _msg: CleanupDeployments, _ctx: &mut Self::Context) -> Self::Result {
+        self.backend.cleanup()
+    }
+}
+
+/// Blocking request: make the rollback deployment the default one.
+pub(crate) struct RollbackDeployment {
+    /// Whether to reboot into the rollback deployment.
+    pub(crate) reboot: bool,
+}
+
+impl Message for RollbackDeployment {
+    type Result = Fallible<()>;
+}
+
+impl Handler<RollbackDeployment> for BlockingClient {
+    type Result = Fallible<()>;
+
+    fn handle(&mut self, msg: RollbackDeployment, _ctx: &mut Self::Context) -> Self::Result {
+        self.backend.rollback(msg.reboot)
+    }
+}
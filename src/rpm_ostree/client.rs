@@ -1,6 +1,12 @@
 //! Asynchronous rpm-ostree client.
 
-use super::blocking::{DbusClient, StageDeployment};
+use super::backend::BackendKind;
+use super::blocking::{
+    BlockingClient, CleanupDeployments, FinalizeDeployment, QueryDeployments, RollbackDeployment,
+    StageDeployment,
+};
+use super::deployment::{DeploymentStatus, Payload};
+use super::progress::TransactionProgress;
 use actix::prelude::*;
 use failure::Error;
 use futures::future;
@@ -16,11 +22,12 @@ lazy_static! {
 /// Main actor for interacting with rpm-ostree.
 #[derive(Clone, Debug)]
 pub struct RpmOstreeClient {
-    pub(crate) pending: Option<libcincinnati::Release>,
-    pub(crate) dbus_client: Option<Addr<DbusClient>>,
+    /// OS-update backend.
+    pub(crate) backend: BackendKind,
+    pub(crate) blocking_client: Option<Addr<BlockingClient>>,
 }
 
-/// Main actor for interacting with Cincinnati server.
+/// Configured client, for system-service registration.
 impl Default for RpmOstreeClient {
     fn default() -> Self {
         let cfg = CONFIGURED.try_read().expect("poisoned lock");
@@ -34,8 +41,9 @@ impl Actor for RpmOstreeClient {
     type Context = Context<Self>;
 
     fn started(&mut self, _ctx: &mut Self::Context) {
-        let addr = actix::sync::SyncArbiter::start(2, DbusClient::default);
-        self.dbus_client = Some(addr);
+        let backend = self.backend.clone();
+        let addr = actix::sync::SyncArbiter::start(2, move || BlockingClient::new(&backend));
+        self.blocking_client = Some(addr);
 
         trace!("rpm-ostree client started");
     }
@@ -44,6 +52,10 @@ impl Actor for RpmOstreeClient {
 /// Rpm-ostree request: stage a deployment.
 pub(crate) struct StageUpdate {
     pub(crate) release: libcincinnati::Release,
+    /// Origin refspec to rebase onto, if switching streams.
+    pub(crate) refspec: Option<String>,
+    /// Recipient for transaction progress, if any.
+    pub(crate) progress: Option<Recipient<TransactionProgress>>,
 }
 
 impl Message for StageUpdate {
@@ -51,16 +63,23 @@ impl Message for StageUpdate {
 }
 
 impl Handler<StageUpdate> for RpmOstreeClient {
-    type Result = Box<Future<Item = Option<libcincinnati::Release>, Error = Error>>;
+    type Result = Box<dyn Future<Item = Option<libcincinnati::Release>, Error = Error>>;
 
     fn handle(&mut self, msg: StageUpdate, _ctx: &mut Self::Context) -> Self::Result {
-        let stage = stage_update(self.dbus_client.clone().unwrap(), msg.release);
+        let stage = stage_update(
+            self.blocking_client.clone().unwrap(),
+            msg.release,
+            msg.refspec,
+            msg.progress,
+        );
         Box::new(stage)
     }
 }
 
 pub(crate) struct FinalizeUpdate {
     pub(crate) release: libcincinnati::Release,
+    /// Recipient for transaction progress, if any.
+    pub(crate) progress: Option<Recipient<TransactionProgress>>,
 }
 
 impl Message for FinalizeUpdate {
@@ -68,52 +87,201 @@ impl Message for FinalizeUpdate {
 }
 
 impl Handler<FinalizeUpdate> for RpmOstreeClient {
-    type Result = Box<Future<Item = Option<libcincinnati::Release>, Error = Error>>;
+    type Result = Box<dyn Future<Item = Option<libcincinnati::Release>, Error = Error>>;
 
     fn handle(&mut self, msg: FinalizeUpdate, _ctx: &mut Self::Context) -> Self::Result {
-        let finalize = finalize_update(self.dbus_client.clone().unwrap(), msg.release);
+        let finalize = finalize_update(
+            self.blocking_client.clone().unwrap(),
+            msg.release,
+            msg.progress,
+        );
         Box::new(finalize)
     }
 }
 
+/// Rpm-ostree request: query booted, staged, pending and rollback deployments.
+pub(crate) struct QueryStatus {}
+
+impl Message for QueryStatus {
+    type Result = Result<DeploymentStatus, Error>;
+}
+
+impl Handler<QueryStatus> for RpmOstreeClient {
+    type Result = Box<dyn Future<Item = DeploymentStatus, Error = Error>>;
+
+    fn handle(&mut self, _msg: QueryStatus, _ctx: &mut Self::Context) -> Self::Result {
+        let status = self
+            .blocking_client
+            .clone()
+            .unwrap()
+            .send(QueryDeployments {})
+            .from_err::<Error>()
+            .flatten()
+            .and_then(DeploymentStatus::try_from_deployments);
+        Box::new(status)
+    }
+}
+
+/// Rpm-ostree request: remove the pending or staged deployment.
+pub(crate) struct CleanupPending {}
+
+impl Message for CleanupPending {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<CleanupPending> for RpmOstreeClient {
+    type Result = Box<dyn Future<Item = (), Error = Error>>;
+
+    fn handle(&mut self, _msg: CleanupPending, _ctx: &mut Self::Context) -> Self::Result {
+        debug!("rpm-ostree, requesting to clean up pending deployment");
+        let cleanup = self
+            .blocking_client
+            .clone()
+            .unwrap()
+            .send(CleanupDeployments {})
+            .from_err::<Error>()
+            .flatten()
+            .inspect(|_| info!("rpm-ostree, cleaned up pending deployment"));
+        Box::new(cleanup)
+    }
+}
+
+/// Rpm-ostree request: roll back to the previous deployment.
+pub(crate) struct RollbackUpdate {
+    /// Whether to reboot into the rollback deployment.
+    pub(crate) reboot: bool,
+}
+
+impl Message for RollbackUpdate {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<RollbackUpdate> for RpmOstreeClient {
+    type Result = Box<dyn Future<Item = (), Error = Error>>;
+
+    fn handle(&mut self, msg: RollbackUpdate, _ctx: &mut Self::Context) -> Self::Result {
+        debug!("rpm-ostree, requesting rollback (reboot: {})", msg.reboot);
+        let rollback = self
+            .blocking_client
+            .clone()
+            .unwrap()
+            .send(RollbackDeployment { reboot: msg.reboot })
+            .from_err::<Error>()
+            .flatten()
+            .inspect(|_| info!("rpm-ostree, rolled back to previous deployment"));
+        Box::new(rollback)
+    }
+}
+
+/// Rpm-ostree request: verify that the staged deployment matches the release payload.
+pub(crate) struct VerifyStagedPayload {
+    pub(crate) release: libcincinnati::Release,
+}
+
+impl Message for VerifyStagedPayload {
+    type Result = Result<bool, Error>;
+}
+
+impl Handler<VerifyStagedPayload> for RpmOstreeClient {
+    type Result = Box<dyn Future<Item = bool, Error = Error>>;
+
+    fn handle(&mut self, msg: VerifyStagedPayload, _ctx: &mut Self::Context) -> Self::Result {
+        let verify = verify_staged_payload(self.blocking_client.clone().unwrap(), msg.release);
+        Box::new(verify)
+    }
+}
+
 fn stage_update(
-    addr: Addr<DbusClient>,
+    addr: Addr<BlockingClient>,
     release: libcincinnati::Release,
+    refspec: Option<String>,
+    progress: Option<Recipient<TransactionProgress>>,
 ) -> impl Future<Item = Option<libcincinnati::Release>, Error = Error> {
-    debug!(
-        "rpm-ostree, requesting to stage update '{}'",
-        release.version()
-    );
+    match refspec {
+        Some(ref r) => debug!(
+            "rpm-ostree, requesting to stage rebase to '{}' at '{}'",
+            r,
+            release.version()
+        ),
+        None => debug!(
+            "rpm-ostree, requesting to stage update '{}'",
+            release.version()
+        ),
+    };
 
-    // TODO(lucab): stage update
-    // https://github.com/projectatomic/rpm-ostree/issues/1748
     future::ok::<_, Error>(release)
         .and_then(move |release| {
-            let req = StageDeployment { release };
+            let req = StageDeployment {
+                release,
+                refspec,
+                progress,
+            };
             addr.send(req).from_err()
         })
         .flatten()
         .inspect(|release| info!("rpm-ostree, staged update '{}'", release.version()))
-        .map(|release| (Some(release)))
+        .map(Some)
 }
 
 fn finalize_update(
-    addr: Addr<DbusClient>,
+    addr: Addr<BlockingClient>,
     release: libcincinnati::Release,
+    progress: Option<Recipient<TransactionProgress>>,
 ) -> impl Future<Item = Option<libcincinnati::Release>, Error = Error> {
     debug!(
-        "rpm-ostree dbus, requesting to finalize deployment '{}'",
+        "rpm-ostree, requesting to finalize deployment '{}'",
         release.version()
     );
 
-    // TODO(lucab): finalize update
-    // https://github.com/projectatomic/rpm-ostree/issues/1748
-    future::ok::<_, Error>(release)
-        .and_then(move |release| {
-            let req = StageDeployment { release };
-            addr.send(req).from_err()
-        })
+    // A successful transaction means the deployment was unlocked and the
+    // reboot started. Deployments are not queried again, as that would race
+    // with the reboot itself.
+    let req = FinalizeDeployment { release, progress };
+    addr.send(req)
+        .from_err::<Error>()
         .flatten()
-        .inspect(|release| info!("rpm-ostree-dbus, finalized update '{}'", release.version()))
-        .map(|release| (Some(release)))
+        .inspect(|release| info!("rpm-ostree, finalized update '{}'", release.version()))
+        .map(Some)
+}
+
+fn verify_staged_payload(
+    addr: Addr<BlockingClient>,
+    release: libcincinnati::Release,
+) -> impl Future<Item = bool, Error = Error> {
+    let payload = future::result(Payload::try_from_release(&release));
+    let staged = addr
+        .send(QueryDeployments {})
+        .from_err::<Error>()
+        .flatten()
+        .map(|deploys| deploys.into_iter().find(|d| d.staged));
+
+    payload
+        .join(staged)
+        .map(move |(payload, staged)| match staged {
+            Some(deploy) => {
+                let matches = payload.matches(&deploy);
+                if matches {
+                    debug!(
+                        "rpm-ostree, staged deployment '{}' matches release '{}'",
+                        deploy.checksum,
+                        release.version()
+                    );
+                } else {
+                    error!(
+                        "rpm-ostree, staged deployment '{}' does not match release '{}' ({:?})",
+                        deploy.checksum,
+                        release.version(),
+                        payload
+                    );
+                }
+                matches
+            }
+            None => {
+                error!(
+                    "rpm-ostree, no staged deployment found for release '{}'",
+                    release.version()
+                );
+                false
+            }
+        })
 }
@@ -0,0 +1,199 @@
+//! Deployments, as reported by rpm-ostree.
+
+use dbus::arg::{RefArg, Variant};
+use failure::Fallible;
+use std::collections::HashMap;
+
+/// Deployment details, as exposed by rpm-ostree over DBus.
+pub(crate) type DbusDeployment = HashMap<String, Variant<Box<dyn RefArg>>>;
+
+/// An rpm-ostree deployment.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct Deployment {
+    /// Deployment ID.
+    pub(crate) id: String,
+    /// OS version.
+    pub(crate) version: String,
+    /// Deployed ostree commit.
+    pub(crate) checksum: String,
+    /// Base ostree commit, if the deployment has layered packages.
+    pub(crate) base_checksum: Option<String>,
+    /// Container image digest, if deployed from a container image.
+    pub(crate) container_digest: Option<String>,
+    /// Origin refspec (e.g. `fedora:fedora/x86_64/coreos/stable`).
+    pub(crate) origin: String,
+    /// Commit timestamp, in seconds since UNIX epoch.
+    pub(crate) timestamp: u64,
+    /// Whether this deployment is pinned against garbage collection.
+    pub(crate) pinned: bool,
+    /// Packages layered on top of the base commit.
+    pub(crate) layered_packages: Vec<String>,
+    /// Whether this deployment is currently booted.
+    pub(crate) booted: bool,
+    /// Whether this deployment is staged for next boot.
+    pub(crate) staged: bool,
+    /// Whether this staged deployment is locked against finalization.
+    pub(crate) finalization_locked: bool,
+}
+
+impl Deployment {
+    /// Parse a deployment from its DBus representation.
+    pub(crate) fn try_from_dbus(input: &DbusDeployment) -> Fallible<Self> {
+        let checksum = dict_str(input, "checksum")
+            .ok_or_else(|| format_err!("deployment without checksum"))?;
+
+        let deploy = Self {
+            id: dict_str(input, "id").unwrap_or_default(),
+            version: dict_str(input, "version").unwrap_or_default(),
+            checksum,
+            base_checksum: dict_str(input, "base-checksum"),
+            container_digest: dict_str(input, "container-image-reference-digest"),
+            origin: dict_str(input, "origin").unwrap_or_default(),
+            timestamp: dict_u64(input, "timestamp").unwrap_or(0),
+            pinned: dict_bool(input, "pinned"),
+            layered_packages: dict_strings(input, "packages"),
+            booted: dict_bool(input, "booted"),
+            staged: dict_bool(input, "staged"),
+            finalization_locked: dict_bool(input, "finalization-locked"),
+        };
+        Ok(deploy)
+    }
+
+    /// Update stream this deployment tracks, from the last component of its origin ref.
+    pub(crate) fn stream(&self) -> Option<&str> {
+        self.origin
+            .rsplit('/')
+            .next()
+            .filter(|s| !s.is_empty() && !s.contains(':'))
+    }
+
+    /// Origin refspec for the same OS tracking another stream.
+    pub(crate) fn refspec_for_stream(&self, stream: &str) -> Fallible<String> {
+        match self.origin.rfind('/') {
+            Some(pos) => Ok(format!("{}/{}", &self.origin[..pos], stream)),
+            None => bail!("cannot derive stream from origin '{}'", self.origin),
+        }
+    }
+
+    /// Whether this deployment carries the payload of a release.
+    pub(crate) fn matches_release(&self, release: &libcincinnati::Release) -> bool {
+        Payload::try_from_release(release)
+            .map(|payload| payload.matches(self))
+            .unwrap_or(false)
+    }
+
+    /// Checksum of the base OS commit (i.e. ignoring layered packages).
+    pub(crate) fn base_commit(&self) -> &str {
+        self.base_checksum.as_ref().unwrap_or(&self.checksum)
+    }
+}
+
+/// Deployments relevant for updates, relative to the booted one.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct DeploymentStatus {
+    /// Currently booted deployment.
+    pub(crate) booted: Deployment,
+    /// Deployment staged for next boot, if any.
+    pub(crate) staged: Option<Deployment>,
+    /// Non-staged deployment to be booted next, if any.
+    pub(crate) pending: Option<Deployment>,
+    /// Deployment to roll back to, if any.
+    pub(crate) rollback: Option<Deployment>,
+}
+
+impl DeploymentStatus {
+    /// Classify deployments, in rpm-ostree order (next boot first).
+    ///
+    /// Pending and rollback deployments are the closest ones before
+    /// and after the booted deployment, respectively.
+    pub(crate) fn try_from_deployments(deploys: Vec<Deployment>) -> Fallible<Self> {
+        let pos = deploys
+            .iter()
+            .position(|d| d.booted)
+            .ok_or_else(|| format_err!("no booted deployment found"))?;
+
+        let mut staged = None;
+        let mut pending = None;
+        for d in &deploys[..pos] {
+            if d.staged {
+                staged = Some(d.clone());
+            } else if pending.is_none() {
+                pending = Some(d.clone());
+            }
+        }
+
+        Ok(Self {
+            booted: deploys[pos].clone(),
+            staged,
+            pending,
+            rollback: deploys.get(pos + 1).cloned(),
+        })
+    }
+}
+
+/// Payload reference of a release.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Payload {
+    /// ostree commit checksum.
+    OstreeCommit(String),
+    /// Container image digest (e.g. `sha256:...`).
+    ContainerDigest(String),
+}
+
+impl Payload {
+    /// Extract the payload reference from release metadata.
+    pub(crate) fn try_from_release(release: &libcincinnati::Release) -> Fallible<Self> {
+        let payload = match release {
+            libcincinnati::Release::Concrete(r) => r.payload.trim(),
+            libcincinnati::Release::Abstract(_) => {
+                bail!("release '{}' has no payload", release.version())
+            }
+        };
+
+        if let Some(pos) = payload.rfind('@') {
+            return Ok(Payload::ContainerDigest(payload[pos + 1..].to_string()));
+        }
+        if payload.starts_with("sha256:") {
+            return Ok(Payload::ContainerDigest(payload.to_string()));
+        }
+        if payload.len() == 64 && payload.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Ok(Payload::OstreeCommit(payload.to_lowercase()));
+        }
+
+        bail!(
+            "unrecognized payload '{}' for release '{}'",
+            payload,
+            release.version()
+        )
+    }
+
+    /// Check whether a deployment matches this payload.
+    pub(crate) fn matches(&self, deploy: &Deployment) -> bool {
+        match self {
+            Payload::OstreeCommit(commit) => commit == deploy.base_commit(),
+            Payload::ContainerDigest(digest) => deploy.container_digest.as_ref() == Some(digest),
+        }
+    }
+}
+
+fn dict_str(dict: &DbusDeployment, key: &str) -> Option<String> {
+    dict.get(key).and_then(|v| v.0.as_str()).map(String::from)
+}
+
+fn dict_u64(dict: &DbusDeployment, key: &str) -> Option<u64> {
+    dict.get(key).and_then(|v| v.0.as_i64()).map(|n| n as u64)
+}
+
+fn dict_strings(dict: &DbusDeployment, key: &str) -> Vec<String> {
+    dict.get(key)
+        .and_then(|v| v.0.as_iter())
+        .map(|items| items.filter_map(|i| i.as_str()).map(String::from).collect())
+        .unwrap_or_default()
+}
+
+fn dict_bool(dict: &DbusDeployment, key: &str) -> bool {
+    dict.get(key)
+        .and_then(|v| v.0.as_i64())
+        .map(|b| b != 0)
+        .unwrap_or(false)
+}
@@ -1,15 +1,38 @@
-mod client;
+mod backend;
 mod blocking;
+mod client;
+mod deployment;
+mod progress;
 
 pub(crate) use client::RpmOstreeClient;
-pub(crate) use client::{FinalizeUpdate, StageUpdate};
+pub(crate) use client::{
+    CleanupPending, FinalizeUpdate, QueryStatus, RollbackUpdate, StageUpdate, VerifyStagedPayload,
+};
+pub(crate) use deployment::{Deployment, DeploymentStatus};
+pub(crate) use progress::TransactionProgress;
 
-pub(crate) fn configure() -> failure::Fallible<()> {
+pub(crate) fn configure(cfg: crate::config::RpmOstreeConfig) -> failure::Fallible<()> {
     let client = RpmOstreeClient {
-        pending: None,
-        dbus_client: None,
+        backend: backend::BackendKind::try_from_config(cfg)?,
+        blocking_client: None,
     };
     let mut static_cfg = client::CONFIGURED.try_write().unwrap();
     *static_cfg = Some(client);
     Ok(())
 }
+
+/// Query the stream of the booted deployment, outside of the actor system.
+///
+/// This is meant for startup-time configuration; failures are logged and ignored.
+pub(crate) fn booted_stream(cfg: &crate::config::RpmOstreeConfig) -> Option<String> {
+    let query = backend::BackendKind::try_from_config(cfg.clone())
+        .and_then(|kind| kind.instantiate().deployments())
+        .and_then(DeploymentStatus::try_from_deployments);
+    match query {
+        Ok(status) => status.booted.stream().map(String::from),
+        Err(e) => {
+            warn!("failed to query booted stream: {}", e);
+            None
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//! Progress of rpm-ostree transactions.
+
+use actix::prelude::*;
+use dbus::MessageItem;
+use std::fmt;
+
+/// Progress event from an rpm-ostree transaction.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub(crate) enum TransactionProgress {
+    /// Informational message.
+    Message { text: String },
+    /// Progress of the current task, in percent.
+    Percent { text: String, percentage: u32 },
+    /// Progress of content download.
+    Download {
+        fetched: u32,
+        requested: u32,
+        bytes_transferred: u64,
+        bytes_per_sec: u64,
+    },
+    /// Transaction completed.
+    Finished { success: bool, error: String },
+    /// No progress observed for too long.
+    Stalled { idle_secs: u64 },
+}
+
+impl Message for TransactionProgress {
+    type Result = ();
+}
+
+impl TransactionProgress {
+    /// Parse a transaction signal, ignoring unknown ones.
+    pub(crate) fn try_from_signal(signal: &dbus::Message) -> Option<Self> {
+        let member = signal.member()?;
+        match &*member {
+            "Message" => {
+                let text: &str = signal.get1()?;
+                Some(TransactionProgress::Message {
+                    text: text.to_string(),
+                })
+            }
+            "PercentProgress" => {
+                let (text, percentage): (Option<&str>, Option<u32>) = signal.get2();
+                Some(TransactionProgress::Percent {
+                    text: text?.to_string(),
+                    percentage: percentage?,
+                })
+            }
+            "DownloadProgress" => Self::try_from_download(&signal.get_items()),
+            "Finished" => {
+                let (success, error): (Option<bool>, Option<&str>) = signal.get2();
+                Some(TransactionProgress::Finished {
+                    success: success?,
+                    error: error.unwrap_or_default().to_string(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Parse `DownloadProgress` arguments.
+    ///
+    /// Only content objects and transfer rate are tracked, from the
+    /// fifth `(uu)` and sixth `(tt)` structures.
+    fn try_from_download(items: &[MessageItem]) -> Option<Self> {
+        let (fetched, requested) = match items.get(4)? {
+            MessageItem::Struct(content) => match (content.first()?, content.get(1)?) {
+                (MessageItem::UInt32(f), MessageItem::UInt32(r)) => (*f, *r),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        let (bytes_transferred, bytes_per_sec) = match items.get(5)? {
+            MessageItem::Struct(transfer) => match (transfer.first()?, transfer.get(1)?) {
+                (MessageItem::UInt64(t), MessageItem::UInt64(s)) => (*t, *s),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some(TransactionProgress::Download {
+            fetched,
+            requested,
+            bytes_transferred,
+            bytes_per_sec,
+        })
+    }
+}
+
+impl fmt::Display for TransactionProgress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransactionProgress::Message { text } => write!(f, "{}", text),
+            TransactionProgress::Percent { text, percentage } => {
+                write!(f, "{} ({}%)", text, percentage)
+            }
+            TransactionProgress::Download {
+                fetched,
+                requested,
+                bytes_transferred,
+                bytes_per_sec,
+            } => write!(
+                f,
+                "downloaded {}/{} objects, {} bytes ({} B/s)",
+                fetched, requested, bytes_transferred, bytes_per_sec
+            ),
+            TransactionProgress::Finished { success: true, .. } => write!(f, "finished"),
+            TransactionProgress::Finished { error, .. } => write!(f, "failed: {}", error),
+            TransactionProgress::Stalled { idle_secs } => {
+                write!(f, "stalled, no progress for {}s", idle_secs)
+            }
+        }
+    }
+}
@@ -6,14 +6,14 @@ use futures::prelude::*;
 pub(crate) struct StratImmediate {}
 
 impl StratImmediate {
-    pub(crate) fn has_green_light(self) -> Box<Future<Item = bool, Error = Error>> {
+    pub(crate) fn has_green_light(self) -> Box<dyn Future<Item = bool, Error = Error>> {
         trace!("green_light check, strategy 'immediate'");
 
         let immediate = future::ok(true);
         Box::new(immediate)
     }
 
-    pub(crate) fn report_steady(self) -> Box<Future<Item = bool, Error = Error>> {
+    pub(crate) fn report_steady(self) -> Box<dyn Future<Item = bool, Error = Error>> {
         trace!("finalizer report steady, strategy 'immediate'");
 
         let immediate = future::ok(true);
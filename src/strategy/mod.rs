@@ -1,7 +1,7 @@
 //! Update and reboot strategies.
 
 use crate::config;
-use crate::update_agent::Identity;
+use crate::update_agent::{Identity, UpdateOutcome};
 use failure::{Error, Fallible};
 use futures::prelude::*;
 
@@ -19,7 +19,7 @@ pub(crate) use remote_http::StratRemoteHTTP;
 
 #[derive(Clone, Debug, Serialize)]
 pub(crate) enum UpStrategy {
-    Http(StratRemoteHTTP),
+    Http(Box<StratRemoteHTTP>),
     Immediate(StratImmediate),
     Never(StratNever),
     Periodic(StratPeriodic),
@@ -39,11 +39,29 @@ impl UpStrategy {
         Ok(strategy)
     }
 
+    /// Strategy name, as in configuration.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            UpStrategy::Http(_) => "remote_http",
+            UpStrategy::Immediate(_) => "immediate",
+            UpStrategy::Never(_) => "never",
+            UpStrategy::Periodic(_) => "periodic",
+        }
+    }
+
+    /// Build long-lived HTTP clients, if any, on agent start.
+    pub(crate) fn build_clients(&mut self, identity: &Identity) -> Fallible<()> {
+        if let UpStrategy::Http(h) = self {
+            h.build_client(identity)?;
+        }
+        Ok(())
+    }
+
     /// Check if finalization is allowed at this time.
     pub(crate) fn has_green_light(
         self,
         identity: Identity,
-    ) -> Box<Future<Item = bool, Error = Error>> {
+    ) -> Box<dyn Future<Item = bool, Error = Error>> {
         match self {
             UpStrategy::Http(h) => h.has_green_light(identity.into()),
             UpStrategy::Immediate(i) => i.has_green_light(),
@@ -53,12 +71,19 @@ impl UpStrategy {
     }
 
     /// Check if this agent is allowed to check for updates at this time.
+    ///
+    /// The outcome of the last update, if any, is reported along.
     pub(crate) fn report_steady(
         self,
         identity: Identity,
-    ) -> Box<Future<Item = bool, Error = Error>> {
+        last_update: Option<UpdateOutcome>,
+    ) -> Box<dyn Future<Item = bool, Error = Error>> {
         match self {
-            UpStrategy::Http(h) => h.report_steady(identity.into()),
+            UpStrategy::Http(h) => {
+                let mut params = remote_http::HttpParams::from(identity);
+                params.update_outcome = last_update;
+                h.report_steady(params)
+            }
             UpStrategy::Immediate(i) => i.report_steady(),
             UpStrategy::Never(n) => n.report_steady(),
             UpStrategy::Periodic(p) => p.finalize(),
@@ -72,7 +97,7 @@ impl UpStrategy {
 
     fn try_remote_http(cfg: config::StratHttpInput) -> Fallible<Self> {
         let remote_http = StratRemoteHTTP::parse(cfg)?;
-        Ok(UpStrategy::Http(remote_http))
+        Ok(UpStrategy::Http(Box::new(remote_http)))
     }
 }
 
@@ -6,14 +6,14 @@ use futures::prelude::*;
 pub(crate) struct StratNever {}
 
 impl StratNever {
-    pub(crate) fn has_green_light(self) -> Box<Future<Item = bool, Error = Error>> {
+    pub(crate) fn has_green_light(self) -> Box<dyn Future<Item = bool, Error = Error>> {
         trace!("green_light check, strategy 'never'");
 
         let never = future::ok(false);
         Box::new(never)
     }
 
-    pub(crate) fn report_steady(self) -> Box<Future<Item = bool, Error = Error>> {
+    pub(crate) fn report_steady(self) -> Box<dyn Future<Item = bool, Error = Error>> {
         trace!("finalizer report steady, strategy 'never'");
 
         let never = future::ok(false);
@@ -6,7 +6,7 @@ use futures::prelude::*;
 pub(crate) struct StratPeriodic {}
 
 impl StratPeriodic {
-    pub(crate) fn finalize(self) -> Box<Future<Item = bool, Error = Error>> {
+    pub(crate) fn finalize(self) -> Box<dyn Future<Item = bool, Error = Error>> {
         trace!("finalizer check, strategy 'immediate'");
 
         let immediate = future::ok(true);
@@ -1,9 +1,10 @@
 use crate::config::StratHttpInput;
-use crate::update_agent::Identity;
-use failure::{Error, Fallible};
+use crate::http::{self, ClientConfig, TlsConfig};
+use crate::metrics;
+use crate::update_agent::{Identity, UpdateOutcome};
+use failure::{Error, Fallible, ResultExt};
 use futures::future;
 use futures::prelude::*;
-use reqwest::r#async as asynchro;
 
 /// Default base URL to the lock manager.
 static DEFAULT_REMOTE_HTTP_BASE: &str = "http://localhost:9999";
@@ -19,7 +20,14 @@ static LOCK_V1_STEADY_STATE_PATH: &str = "v1/steady-state";
 pub(crate) struct StratRemoteHTTP {
     /// Base URL to the lock manager.
     #[serde(with = "url_serde")]
-    pub(crate) base_url: reqwest::Url,
+    pub(crate) base_url: url::Url,
+    /// TLS configuration for the lock-manager client.
+    pub(crate) tls: TlsConfig,
+    /// HTTP configuration for the lock-manager client.
+    pub(crate) client_cfg: ClientConfig,
+    /// Long-lived HTTP client, shared across requests (built on agent start).
+    #[serde(skip)]
+    client: Option<http::Client>,
 }
 
 impl StratRemoteHTTP {
@@ -30,14 +38,30 @@ impl StratRemoteHTTP {
         } else {
             cfg.base_url
         };
+        let base_url = url::Url::parse(&base_url)?;
+
+        let tls = TlsConfig::try_from_input(cfg.tls, base_url.scheme() == "https")
+            .context("invalid lock-manager TLS configuration")?;
+        let client_cfg = ClientConfig::try_from_input(cfg.client)
+            .context("invalid lock-manager HTTP configuration")?;
 
         let remote = Self {
-            base_url: reqwest::Url::parse(&base_url)?,
+            base_url,
+            tls,
+            client_cfg,
+            client: None,
         };
 
         Ok(remote)
     }
 
+    /// Build the lock-manager client, identifying as the given node.
+    pub(crate) fn build_client(&mut self, identity: &Identity) -> Fallible<()> {
+        let client = self.client_cfg.build(&self.tls, &identity.user_agent())?;
+        self.client = Some(client);
+        Ok(())
+    }
+
     /// Check if finalization is allowed.
     ///
     /// This POSTs to a remote reboot manager in order to check
@@ -46,13 +70,19 @@ impl StratRemoteHTTP {
     pub(crate) fn has_green_light(
         self,
         params: HttpParams,
-    ) -> Box<Future<Item = bool, Error = Error>> {
+    ) -> Box<dyn Future<Item = bool, Error = Error>> {
         trace!("finalizer check, strategy 'remote_http'");
         trace!("finalizer client parameters: {:?}", params.client_params);
 
         // A positive response (status: 200) from the remote manager
         // is the definitive green-light to proceed with update finalization.
-        let green_light = self.post_to_manager(LOCK_V1_PRE_REBOOT_PATH, params);
+        let green_light = self
+            .post_to_manager(LOCK_V1_PRE_REBOOT_PATH, params)
+            .inspect(|ok| {
+                if !ok {
+                    metrics::record_lock_denial();
+                }
+            });
 
         Box::new(green_light)
     }
@@ -65,7 +95,7 @@ impl StratRemoteHTTP {
     pub(crate) fn report_steady(
         self,
         params: HttpParams,
-    ) -> Box<Future<Item = bool, Error = Error>> {
+    ) -> Box<dyn Future<Item = bool, Error = Error>> {
         trace!("report steady state, strategy 'remote_http'");
         trace!("steady state client parameters: {:?}", params.client_params);
 
@@ -81,31 +111,53 @@ impl StratRemoteHTTP {
         self,
         path: &'static str,
         params: HttpParams,
-    ) -> Box<Future<Item = bool, Error = Error>> {
+    ) -> Box<dyn Future<Item = bool, Error = Error>> {
         // POST to remote manager endpoint.
         let endpoint = match self.base_url.join(path) {
             Ok(url) => url,
             Err(e) => return Box::new(future::err(format_err!("{}", e))),
         };
+        let client = match self.client {
+            Some(ref client) => client,
+            None => return Box::new(future::err(format_err!("lock-manager client not built"))),
+        };
         trace!("POST to remote manager: {}", endpoint);
-        let req = asynchro::Client::new().post(endpoint).json(&params).send();
-
-        // Ensure response is positive.
-        let resp = req
-            .and_then(|resp| resp.error_for_status())
+        let req = client.post_json(endpoint, &params);
+
+        // A refusal from the remote manager is an answer, not an error;
+        // any other non-successful response is.
+        let is_ok = req
+            .and_then(|resp| {
+                let status = resp.status();
+                if is_refusal(status) {
+                    debug!("remote_http: request refused with status {}", status);
+                    return Ok(false);
+                }
+                // Ensure response status is 200.
+                http::check_status(resp).map(|r| r.status() == hyper::StatusCode::OK)
+            })
             .map_err(|err| {
                 error!("remote_http: {}", err);
                 err
-            })
-            .from_err();
-
-        // Ensure response status is 200.
-        let is_ok = resp.map(|r| r.status() == reqwest::StatusCode::OK);
+            });
 
         Box::new(is_ok)
     }
 }
 
+/// Whether a response status is a refusal from the lock manager.
+///
+/// Only explicit denials are refusals; any other failure (e.g. a bad
+/// request, or rate limiting) is an error, retried with backoff.
+fn is_refusal(status: hyper::StatusCode) -> bool {
+    match status {
+        hyper::StatusCode::FORBIDDEN | hyper::StatusCode::CONFLICT | hyper::StatusCode::LOCKED => {
+            true
+        }
+        _ => false,
+    }
+}
+
 /// Client parameters for requests to the lock manager.
 #[derive(Clone, Debug, Serialize)]
 struct ClientParams {
@@ -121,6 +173,9 @@ struct ClientParams {
 #[derive(Clone, Debug, Serialize)]
 pub(crate) struct HttpParams {
     client_params: ClientParams,
+    /// Outcome of the last update, reported with steady state.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) update_outcome: Option<UpdateOutcome>,
 }
 
 impl From<Identity> for HttpParams {
@@ -130,6 +185,27 @@ impl From<Identity> for HttpParams {
             group: identity.group,
             node_uuid: identity.node_uuid.to_string(),
         };
-        Self { client_params }
+        Self {
+            client_params,
+            update_outcome: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::StatusCode;
+
+    #[test]
+    fn test_is_refusal() {
+        assert!(is_refusal(StatusCode::CONFLICT));
+        assert!(is_refusal(StatusCode::FORBIDDEN));
+        assert!(is_refusal(StatusCode::LOCKED));
+        assert!(!is_refusal(StatusCode::OK));
+        assert!(!is_refusal(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!is_refusal(StatusCode::BAD_REQUEST));
+        assert!(!is_refusal(StatusCode::NOT_FOUND));
+        assert!(!is_refusal(StatusCode::SERVICE_UNAVAILABLE));
     }
 }
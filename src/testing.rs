@@ -0,0 +1,15 @@
+//! Helpers shared by unit tests.
+
+/// Concrete release, with a payload made of a repeated `commit` character.
+pub(crate) fn concrete_release(version: &str, commit: char) -> libcincinnati::ConcreteRelease {
+    libcincinnati::ConcreteRelease {
+        version: version.to_string(),
+        payload: commit.to_string().repeat(64),
+        metadata: Default::default(),
+    }
+}
+
+/// Graph release, see `concrete_release`.
+pub(crate) fn release(version: &str, commit: char) -> libcincinnati::Release {
+    libcincinnati::Release::Concrete(concrete_release(version, commit))
+}
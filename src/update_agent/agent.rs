@@ -1,7 +1,13 @@
 //! Update agent.
 
+use super::blocklist::Blocklist;
 use super::identity::Identity;
+use super::persist::{self, LockInfo, PersistedRelease, PersistedState, StateFile};
+use super::schedule::{Scheduler, Subsystem};
+use super::status::{InFlight, StatusWatch, UpdateOutcome};
 use crate::cincinnati;
+use crate::health;
+use crate::metrics;
 use crate::rpm_ostree;
 use crate::strategy;
 use actix::prelude::*;
@@ -9,9 +15,13 @@ use failure::Error;
 use futures::future;
 use futures::prelude::*;
 use lazy_static::lazy_static;
+use std::path::PathBuf;
 use std::sync;
 use std::time;
 
+/// Time after which a pending reboot is considered overdue.
+static REBOOT_OVERDUE_SECS: u64 = 10 * 60;
+
 lazy_static! {
     pub(crate) static ref CONFIGURED: sync::RwLock<Option<UpdateAgent>> = sync::RwLock::default();
 }
@@ -19,9 +29,37 @@ lazy_static! {
 #[derive(Clone, Debug)]
 pub(crate) struct UpdateAgent {
     pub(crate) identity: Identity,
-    pub(crate) refresh_period: time::Duration,
+    pub(crate) scheduler: Scheduler,
     pub(crate) strategy: strategy::UpStrategy,
     pub(crate) state: UpdateAgentState,
+    /// State transition in progress, if any.
+    pub(crate) in_flight: Option<InFlight>,
+    /// Persistent state location.
+    pub(crate) state_file: StateFile,
+    /// Time of the last state transition.
+    pub(crate) state_since: time::SystemTime,
+    /// Finalization lock held by this agent, if any.
+    pub(crate) lock: Option<LockInfo>,
+    /// Outcome of the last update, if any.
+    pub(crate) last_update: Option<UpdateOutcome>,
+    /// Whether the update strategy acknowledged the last update outcome.
+    pub(crate) last_update_reported: bool,
+    /// Local blocklist of bad releases.
+    pub(crate) blocklist: Blocklist,
+    /// Whether updates are paused by an operator.
+    pub(crate) paused: bool,
+    /// Whether an operator forced finalization of the staged update.
+    pub(crate) force_finalize: bool,
+    /// Next scheduled refresh, if any.
+    pub(crate) next_tick: Option<SpawnHandle>,
+    /// Booted version, as of the last update check.
+    pub(crate) booted_version: Option<String>,
+    /// Time of the last update check, if any.
+    pub(crate) last_check: Option<time::SystemTime>,
+    /// Prometheus textfile to keep up to date, if any.
+    pub(crate) metrics_textfile: Option<PathBuf>,
+    /// Status updates for watchers outside the actix system.
+    pub(crate) status_watch: StatusWatch,
 }
 
 #[derive(Clone, Debug)]
@@ -30,12 +68,43 @@ pub(crate) enum UpdateAgentState {
     StartState,
     /// Actor has been successfully initialized.
     Initialization,
-    /// Actor is checking and waiting for updates.
-    Steady,
+    /// Steady state reported, checking for updates.
+    ReportedSteady,
+    /// No update available at last check, waiting for updates.
+    NoNewUpdate,
+    /// Staging this release failed, after the given number of attempts.
+    StagingFailed(libcincinnati::Release, u32),
     /// Update found and staged.
     UpdateStaged(libcincinnati::Release),
-    /// Update finalized.
-    UpdateFinalized(libcincinnati::Release),
+    /// Finalizing this release failed, after the given number of attempts.
+    FinalizationFailed(libcincinnati::Release, u32),
+    /// Update finalized, waiting for reboot since the given time, after
+    /// the given number of failed finalization attempts.
+    RebootPending(libcincinnati::Release, time::SystemTime, u32),
+    /// Rebooted into update, running health checks since the given time.
+    HealthCheck(libcincinnati::Release, time::SystemTime),
+    /// Update failed health checks, rolling back since the given time.
+    RollbackPending(libcincinnati::Release, time::SystemTime),
+    /// Update abandoned for the given reason, operator action required.
+    Abandoned(libcincinnati::Release, String),
+}
+
+impl UpdateAgentState {
+    /// Name of the transition attempted from this state.
+    fn operation(&self) -> &'static str {
+        match self {
+            UpdateAgentState::StartState => "initialize",
+            UpdateAgentState::Initialization => "report-steady",
+            UpdateAgentState::ReportedSteady | UpdateAgentState::NoNewUpdate => "check-and-stage",
+            UpdateAgentState::StagingFailed(..) => "retry-stage",
+            UpdateAgentState::UpdateStaged(_) => "finalize",
+            UpdateAgentState::FinalizationFailed(..) => "retry-finalize",
+            UpdateAgentState::RebootPending(..) => "await-reboot",
+            UpdateAgentState::HealthCheck(..) => "check-health",
+            UpdateAgentState::RollbackPending(..) => "rollback",
+            UpdateAgentState::Abandoned(..) => "idle",
+        }
+    }
 }
 
 impl Default for UpdateAgent {
@@ -50,10 +119,11 @@ impl Actor for UpdateAgent {
 
     fn started(&mut self, ctx: &mut Self::Context) {
         trace!("update agent started");
+        self.publish_status();
 
-        // Schedule periodical refresh.
+        // Kick off the first refresh; following ones are scheduled
+        // after each tick completes.
         ctx.notify(RefreshTick {});
-        ctx.run_interval(self.refresh_period, |_act, ctx| ctx.notify(RefreshTick {}));
     }
 }
 
@@ -72,99 +142,959 @@ impl Handler<RefreshTick> for UpdateAgent {
     fn handle(&mut self, msg: RefreshTick, _ctx: &mut Self::Context) -> Self::Result {
         trace!("update agent tick, current state: {:?}", self.state);
 
-        match self.state {
+        // Serialize ticks: at most one transition can be in progress.
+        // The next tick is scheduled by the one in flight, on completion.
+        if let Some(ref op) = self.in_flight {
+            debug!(
+                "skipping tick, operation '{}' in progress since {:?}",
+                op.operation, op.started
+            );
+            return Box::new(actix::fut::ok(()));
+        }
+        let previous = self.transition_key();
+        self.in_flight = Some(InFlight {
+            operation: self.state.operation(),
+            started: time::SystemTime::now(),
+            progress: None,
+        });
+
+        let tick = match self.state {
             UpdateAgentState::StartState => self.try_initialize(msg),
             UpdateAgentState::Initialization => self.try_steady(msg),
-            UpdateAgentState::Steady => self.try_stage_update(msg),
-            UpdateAgentState::UpdateStaged(ref r) => self.try_finalize_update(msg, r.clone()),
-            UpdateAgentState::UpdateFinalized(_) => Box::new(actix::fut::ok(())),
-        }
+            UpdateAgentState::ReportedSteady
+            | UpdateAgentState::NoNewUpdate
+            | UpdateAgentState::StagingFailed(..)
+                if self.paused =>
+            {
+                self.skip_paused()
+            }
+            UpdateAgentState::UpdateStaged(_) | UpdateAgentState::FinalizationFailed(..)
+                if self.paused && !self.force_finalize =>
+            {
+                self.skip_paused()
+            }
+            UpdateAgentState::ReportedSteady
+            | UpdateAgentState::NoNewUpdate
+            | UpdateAgentState::StagingFailed(..) => self.try_stage_update(msg),
+            UpdateAgentState::UpdateStaged(ref r)
+            | UpdateAgentState::FinalizationFailed(ref r, _) => {
+                self.try_finalize_update(msg, r.clone())
+            }
+            UpdateAgentState::RebootPending(ref r, since, _) => self.await_reboot(r.clone(), since),
+            UpdateAgentState::HealthCheck(ref r, since) => {
+                self.try_check_health(msg, r.clone(), since)
+            }
+            UpdateAgentState::RollbackPending(ref r, since) => self.try_rollback(r.clone(), since),
+            UpdateAgentState::Abandoned(..) => Box::new(actix::fut::ok(())),
+        };
+
+        // Schedule next refresh, based on this tick outcome.
+        let scheduled = tick.then(move |outcome, actor, ctx| {
+            trace!("tick completed, status: {:?}", actor.status());
+            if let Some(op) = actor.in_flight.take() {
+                trace!(
+                    "operation '{}' completed in {:?}",
+                    op.operation,
+                    op.started.elapsed().unwrap_or_default()
+                );
+            }
+            actor.scheduler.record_outcome(&outcome);
+            if let Err(ref e) = outcome {
+                // Untagged errors come from local operations (e.g. the state
+                // file or the blocklist), which are not accounted anywhere.
+                match Subsystem::of(e) {
+                    Some(s) => metrics::record_error(s),
+                    None => trace!("not accounting local error: {}", e),
+                }
+            }
+            // Persist on transitions, and on errors to keep failure counters.
+            if actor.transition_key() != previous {
+                actor.state_since = time::SystemTime::now();
+                actor.persist_state();
+            } else if outcome.is_err() {
+                actor.persist_state();
+            }
+            if let Some(ref path) = actor.metrics_textfile {
+                if let Err(e) = metrics::write_textfile(path, &actor.status()) {
+                    warn!("failed to write metrics: {}", e);
+                }
+            }
+            actor.publish_status();
+            let delay = actor.scheduler.next_delay();
+            trace!("next refresh in {:?}", delay);
+            let next_tick = ctx.run_later(delay, |_act, ctx| ctx.notify(RefreshTick {}));
+            actor.next_tick = Some(next_tick);
+            actix::fut::result(outcome)
+        });
+        Box::new(scheduled)
     }
 }
 impl UpdateAgent {
     /// Try to initialize the update agent.
+    ///
+    /// Persisted state is reloaded and reconciled against actual deployments.
+    /// Otherwise, if a deployment was already staged, resume from it when it
+    /// matches a release in the current stream, or clean it up.
     fn try_initialize(&mut self, _msg: RefreshTick) -> ResponseActFuture<Self, (), Error> {
-        // TODO(lucab): double-check if initialization needs more crash-recovery logic.
-        // If not, maybe get rid of `StartState`.
-        let empty = future::ok(());
-        let initialization = actix::fut::wrap_future::<_, Self>(empty).map(|_r, actor, _ctx| {
-            actor.state = UpdateAgentState::Initialization;
-        });
+        let persisted = match self.state_file.load() {
+            Ok(p) => p,
+            Err(e) => {
+                error!("ignoring persisted state: {}", e);
+                None
+            }
+        };
+        if let Some(ref p) = persisted {
+            debug!("reloaded persisted state '{}'", p.state);
+            self.scheduler.restore_failures(&p.failures);
+            self.lock = p.lock.clone();
+            self.last_update = p.last_update.clone();
+            self.last_update_reported = p.last_update_reported;
+            self.paused = p.paused;
+            if self.paused {
+                warn!("updates are paused");
+            }
+        }
+
+        let configured = self.identity.stream.clone();
+        let recovery =
+            rpm_ostree_status().and_then(move |status| reconcile(status, persisted, configured));
+
+        let initialization =
+            actix::fut::wrap_future::<_, Self>(recovery).map(|recovery, actor, _ctx| {
+                actor.state = match recovery {
+                    Recovery::Fresh => UpdateAgentState::Initialization,
+                    Recovery::Staged(r) => {
+                        info!("resuming with staged update '{}'", r.version());
+                        UpdateAgentState::UpdateStaged(r)
+                    }
+                    Recovery::StagingFailed(r, n) => {
+                        info!(
+                            "resuming staging of update '{}' after {} failed attempt(s)",
+                            r.version(),
+                            n
+                        );
+                        UpdateAgentState::StagingFailed(r, n)
+                    }
+                    Recovery::FinalizationFailed(r, n) => {
+                        info!(
+                            "resuming finalization of update '{}' after {} failed attempt(s)",
+                            r.version(),
+                            n
+                        );
+                        UpdateAgentState::FinalizationFailed(r, n)
+                    }
+                    Recovery::Finalized(r) => {
+                        info!("resuming with finalized update '{}'", r.version());
+                        UpdateAgentState::RebootPending(r, time::SystemTime::now(), 0)
+                    }
+                    Recovery::Rebooted(r, outcome) => actor.rebooted(r, outcome),
+                    Recovery::HealthCheck(r, since) => {
+                        info!("resuming health checks for update '{}'", r.version());
+                        UpdateAgentState::HealthCheck(r, since)
+                    }
+                    Recovery::RollbackPending(r) => {
+                        warn!("resuming rollback from update '{}'", r.version());
+                        UpdateAgentState::RollbackPending(r, time::SystemTime::now())
+                    }
+                    Recovery::Abandoned(r, reason) => {
+                        warn!("update '{}' previously abandoned: {}", r.version(), reason);
+                        UpdateAgentState::Abandoned(r, reason)
+                    }
+                };
+            });
 
         Box::new(initialization)
     }
 
     /// Try to report agent readiness and move to steady state.
     fn try_steady(&mut self, _msg: RefreshTick) -> ResponseActFuture<Self, (), Error> {
-        let report_steady = self.strategy.clone().report_steady(self.identity.clone());
+        // The update outcome is only reported until acknowledged.
+        let last_update = if self.last_update_reported {
+            None
+        } else {
+            self.last_update.clone()
+        };
+        let report_steady = self
+            .strategy
+            .clone()
+            .report_steady(self.identity.clone(), last_update)
+            .map_err(|e| Subsystem::Strategy.tag(e));
 
         let steady_state =
             actix::fut::wrap_future::<_, Self>(report_steady).map(|is_ok, actor, _ctx| {
                 if is_ok {
                     info!("steady state confirmed");
-                    actor.state = UpdateAgentState::Steady;
+                    actor.lock = None;
+                    actor.last_update_reported = actor.last_update.is_some();
+                    actor.state = UpdateAgentState::ReportedSteady;
                 }
             });
 
         Box::new(steady_state)
     }
 
+    /// Run post-reboot health checks, before reporting steady state.
+    ///
+    /// Failing checks are retried until the grace period expires, then
+    /// the release is marked as bad and rolled back.
+    fn try_check_health(
+        &mut self,
+        _msg: RefreshTick,
+        release: libcincinnati::Release,
+        since: time::SystemTime,
+    ) -> ResponseActFuture<Self, (), Error> {
+        let checks = actix::fut::wrap_future::<_, Self>(health_run_checks(since));
+        let checked = checks.map(move |report, actor, _ctx| {
+            if report.is_healthy() {
+                info!("health checks passed for update '{}'", release.version());
+                actor.state = UpdateAgentState::Initialization;
+                return;
+            }
+            for failure in &report.failures {
+                warn!("health check failed: {}", failure);
+            }
+            if !report.grace_expired {
+                return;
+            }
+
+            error!(
+                "health checks still failing for update '{}', rolling back",
+                release.version()
+            );
+            actor.block_release(&release, "failed health checks");
+            if let Some(ref mut outcome) = actor.last_update {
+                outcome.success = false;
+                actor.last_update_reported = false;
+            }
+            actor.state = UpdateAgentState::RollbackPending(release, time::SystemTime::now());
+        });
+
+        Box::new(checked)
+    }
+
+    /// Roll back to the previous deployment and reboot, unless already done.
+    fn try_rollback(
+        &self,
+        release: libcincinnati::Release,
+        since: time::SystemTime,
+    ) -> ResponseActFuture<Self, (), Error> {
+        let rollback = rpm_ostree_status().and_then(move |status| match status.pending {
+            Some(ref next) if !next.matches_release(&release) => {
+                debug!(
+                    "rollback from '{}' pending since {}s",
+                    release.version(),
+                    since.elapsed().unwrap_or_default().as_secs()
+                );
+                future::Either::A(future::ok(()))
+            }
+            _ => future::Either::B(rpm_ostree_rollback()),
+        });
+
+        Box::new(actix::fut::wrap_future(rollback))
+    }
+
     /// Check for any available update and try to stage it.
+    ///
+    /// If a stream is explicitly configured and differs from the booted
+    /// one, this stages a rebase to the target stream instead. Staging
+    /// failures are retried, up to the configured budget.
     fn try_stage_update(&mut self, _msg: RefreshTick) -> ResponseActFuture<Self, (), Error> {
-        let stage_update = cincinnati_check_update().and_then(|next| match next {
-            Some(release) => future::Either::A(rpm_ostree_stage(release)),
-            None => future::Either::B(future::ok(None)),
+        let configured = self.identity.stream.clone();
+        let stage_update = rpm_ostree_status().and_then(move |status| {
+            let booted = status.booted;
+            let booted_version = booted.version.clone();
+            let current = booted.stream().map(String::from);
+            let stage: Box<dyn Future<Item = StageOutcome, Error = Error>> =
+                match (configured, current) {
+                    (Some(stream), Some(ref current)) if stream != *current => {
+                        info!(
+                            "configured stream '{}' differs from booted stream '{}'",
+                            stream, current
+                        );
+                        stage_stream_rebase(booted, stream)
+                    }
+                    (_, Some(current)) => stage_next_update(current, booted_version.clone()),
+                    (Some(stream), None) => stage_next_update(stream, booted_version.clone()),
+                    (None, None) => Box::new(future::err(format_err!(
+                        "no stream configured, and booted origin '{}' does not track any",
+                        booted.origin
+                    ))),
+                };
+            stage.map(move |outcome| (booted_version, outcome))
         });
 
-        let staged =
-            actix::fut::wrap_future::<_, Self>(stage_update).map(|release, actor, _ctx| {
-                if let Some(r) = release {
-                    actor.state = UpdateAgentState::UpdateStaged(r);
-                }
-            });
+        let staged = actix::fut::wrap_future::<_, Self>(stage_update).map(
+            |(booted_version, outcome), actor, _ctx| {
+                actor.booted_version = Some(booted_version);
+                actor.last_check = Some(time::SystemTime::now());
+                actor.staged(outcome);
+            },
+        );
 
         Box::new(staged)
     }
 
+    /// Progress to next state after an update check.
+    fn staged(&mut self, outcome: StageOutcome) {
+        match outcome {
+            StageOutcome::Staged(r) => {
+                metrics::record_stage();
+                self.state = UpdateAgentState::UpdateStaged(r);
+            }
+            StageOutcome::NoUpdate => self.state = UpdateAgentState::NoNewUpdate,
+            StageOutcome::Failed(r, e) => self.staging_failed(r, e),
+        }
+    }
+
     /// Check for finalization green-flag and try to finalize the update.
+    ///
+    /// While waiting for the green-flag, the graph is polled again so
+    /// that a stale staged release gets replaced by a newer one.
     fn try_finalize_update(
         &mut self,
         _msg: RefreshTick,
         release: libcincinnati::Release,
     ) -> ResponseActFuture<Self, (), Error> {
+        // Ensure the staged deployment matches the release payload.
+        let verify = rpm_ostree_verify_staged(release.clone());
+
         // Check if finalization is allowed at this time.
-        let green_light = self.strategy.clone().has_green_light(self.identity.clone());
+        let configured = self.identity.stream.clone();
+        let strategy = self.strategy.clone();
+        let identity = self.identity.clone();
+        let forced = std::mem::replace(&mut self.force_finalize, false);
+        let green_light = verify.and_then(move |verified| {
+            if verified && forced {
+                warn!("finalization forced by operator, bypassing update strategy");
+                future::Either::B(future::ok(Some(true)))
+            } else if verified {
+                let green_light = strategy
+                    .has_green_light(identity)
+                    .map_err(|e| Subsystem::Strategy.tag(e));
+                future::Either::A(green_light.map(Some))
+            } else {
+                future::Either::B(future::ok(None))
+            }
+        });
 
-        // Try to finalize.
-        let finalize = green_light.and_then(move |ok| {
-            if ok {
+        let decision = green_light.and_then(move |ok| match ok {
+            Some(true) => {
                 info!("green-light for finalization");
-                future::Either::A(rpm_ostree_finalize(release))
-            } else {
+                future::Either::A(future::ok(FinalizeOutcome::Ready(release)))
+            }
+            Some(false) => {
                 trace!("finalization not allowed now");
-                future::Either::B(future::ok(None))
+                let refresh = refresh_staged(release, configured);
+                future::Either::B(future::Either::A(refresh))
             }
+            None => future::Either::B(future::Either::B(future::ok(FinalizeOutcome::Mismatch(
+                release,
+            )))),
         });
 
+        // Try to finalize. The pending reboot is persisted beforehand, as
+        // the reboot may start before rpm-ostree even replies.
+        let finalize = actix::fut::wrap_future::<_, Self>(decision).and_then(
+            |outcome, actor, _ctx| -> ResponseActFuture<Self, FinalizeOutcome, Error> {
+                let release = match outcome {
+                    FinalizeOutcome::Ready(r) => r,
+                    outcome => return Box::new(actix::fut::ok(outcome)),
+                };
+                let previous = (actor.state.clone(), actor.state_since, actor.lock.clone());
+                actor.reboot_pending(release.clone());
+
+                let finalize = rpm_ostree_finalize(release.clone()).then(|res| match res {
+                    Ok(r) => Ok(FinalizeOutcome::Finalized(r)),
+                    Err(e) => Ok(FinalizeOutcome::Failed(release, e)),
+                });
+                let finalized = actix::fut::wrap_future::<_, Self>(finalize).map(
+                    move |outcome, actor, _ctx| {
+                        match outcome {
+                            FinalizeOutcome::Finalized(Some(_)) => {}
+                            _ => {
+                                // Nothing was finalized, roll back the pending reboot.
+                                let (state, since, lock) = previous;
+                                actor.state = state;
+                                actor.state_since = since;
+                                actor.lock = lock;
+                                actor.persist_state();
+                            }
+                        };
+                        outcome
+                    },
+                );
+                Box::new(finalized)
+            },
+        );
+
         // Progress to next state.
-        let finalized = actix::fut::wrap_future::<_, Self>(finalize).map(|release, actor, _ctx| {
-            if let Some(r) = release {
-                actor.state = UpdateAgentState::UpdateFinalized(r);
+        let finalized = finalize.map(|outcome, actor, _ctx| match outcome {
+            FinalizeOutcome::Finalized(Some(r)) => {
+                info!("reboot pending into '{}'", r.version());
+                metrics::record_finalize();
             }
-            // else { self.try_stage_update(_msg) }
+            FinalizeOutcome::Mismatch(r) => {
+                error!(
+                    "refusing to finalize update '{}': staged deployment does not match payload",
+                    r.version()
+                );
+                actor.abandon(r, "staged deployment does not match payload".to_string());
+            }
+            FinalizeOutcome::Failed(r, e) => actor.finalization_failed(r, e),
+            FinalizeOutcome::Restaged(outcome) => actor.staged(outcome),
+            FinalizeOutcome::Finalized(None)
+            | FinalizeOutcome::Ready(_)
+            | FinalizeOutcome::Waiting => {}
         });
 
         Box::new(finalized)
     }
+
+    /// Record a failed staging attempt, abandoning the release once out of budget.
+    fn staging_failed(&mut self, release: libcincinnati::Release, err: Error) {
+        let attempts = match self.state {
+            UpdateAgentState::StagingFailed(ref r, n) if r.version() == release.version() => n + 1,
+            _ => 1,
+        };
+        let budget = self.scheduler.max_staging_attempts();
+        metrics::record_error(Subsystem::RpmOstree);
+        error!(
+            "failed to stage update '{}' (attempt {}/{}): {}",
+            release.version(),
+            attempts,
+            budget,
+            err
+        );
+
+        if attempts >= budget {
+            let reason = format!("staging failed {} times: {}", attempts, err);
+            self.abandon(release, reason);
+        } else {
+            self.state = UpdateAgentState::StagingFailed(release, attempts);
+        }
+    }
+
+    /// Record a failed finalization attempt, abandoning the release once out of budget.
+    fn finalization_failed(&mut self, release: libcincinnati::Release, err: Error) {
+        let attempts = match self.state {
+            UpdateAgentState::FinalizationFailed(ref r, n)
+            | UpdateAgentState::RebootPending(ref r, _, n)
+                if r.version() == release.version() =>
+            {
+                n + 1
+            }
+            _ => 1,
+        };
+        let budget = self.scheduler.max_finalization_attempts();
+        metrics::record_error(Subsystem::RpmOstree);
+        error!(
+            "failed to finalize update '{}' (attempt {}/{}): {}",
+            release.version(),
+            attempts,
+            budget,
+            err
+        );
+
+        if attempts >= budget {
+            let reason = format!("finalization failed {} times: {}", attempts, err);
+            self.abandon(release, reason);
+        } else {
+            self.state = UpdateAgentState::FinalizationFailed(release, attempts);
+        }
+    }
+
+    /// Give up on a release, until an operator intervenes.
+    fn abandon(&mut self, release: libcincinnati::Release, reason: String) {
+        error!(
+            "abandoning update '{}', operator action required: {}",
+            release.version(),
+            reason
+        );
+        self.block_release(&release, &reason);
+        self.state = UpdateAgentState::Abandoned(release, reason);
+    }
+
+    /// Skip a tick while updates are paused.
+    fn skip_paused(&self) -> ResponseActFuture<Self, (), Error> {
+        debug!("updates paused, skipping '{}'", self.state.operation());
+        Box::new(actix::fut::ok(()))
+    }
+
+    /// State label, target version and attempts, changing on every transition.
+    fn transition_key(&self) -> (&'static str, Option<String>, Option<u32>) {
+        let target = self.state.target().map(|r| r.version().to_string());
+        (self.state.label(), target, self.state.attempts())
+    }
+
+    /// Save current state to disk, logging any failure.
+    pub(crate) fn persist_state(&self) {
+        let state = PersistedState {
+            state: self.state.label().to_string(),
+            target: self.state.target().and_then(PersistedRelease::from_release),
+            state_since: persist::epoch_secs(self.state_since),
+            failures: self.scheduler.failures(),
+            lock: self.lock.clone(),
+            last_update: self.last_update.clone(),
+            last_update_reported: self.last_update_reported,
+            paused: self.paused,
+            reason: match self.state {
+                UpdateAgentState::Abandoned(_, ref reason) => Some(reason.clone()),
+                _ => None,
+            },
+            attempts: self.state.attempts(),
+            boot_id: persist::current_boot_id(),
+        };
+        if let Err(e) = self.state_file.save(&state) {
+            error!("failed to persist agent state: {}", e);
+        }
+    }
+
+    /// Record the finalization lock and persist the pending reboot.
+    fn reboot_pending(&mut self, release: libcincinnati::Release) {
+        let now = time::SystemTime::now();
+        let attempts = match self.state {
+            UpdateAgentState::FinalizationFailed(_, n) => n,
+            _ => 0,
+        };
+        self.lock = Some(LockInfo {
+            strategy: self.strategy.name().to_string(),
+            acquired_at: persist::epoch_secs(now),
+        });
+        self.state = UpdateAgentState::RebootPending(release, now, attempts);
+        self.state_since = now;
+        self.persist_state();
+    }
+
+    /// Add a failed release to the blocklist, logging any failure.
+    fn block_release(&self, release: &libcincinnati::Release, reason: &str) {
+        if let Err(e) = self.blocklist.add(release.version(), reason) {
+            error!("failed to blocklist release '{}': {}", release.version(), e);
+        }
+    }
+
+    /// Record the outcome of a reboot after finalization, and return the next state.
+    ///
+    /// Successful updates move on to health checks, failed ones are blocklisted.
+    fn rebooted(
+        &mut self,
+        release: libcincinnati::Release,
+        outcome: UpdateOutcome,
+    ) -> UpdateAgentState {
+        let success = outcome.success;
+        if success {
+            info!("rebooted into update '{}'", outcome.target_version);
+        } else {
+            error!(
+                "update to '{}' failed, booted into '{}' instead",
+                outcome.target_version, outcome.booted_version
+            );
+            let reason = format!("booted into '{}'", outcome.booted_version);
+            self.block_release(&release, &reason);
+        }
+        self.last_update = Some(outcome);
+        self.last_update_reported = false;
+        if success {
+            UpdateAgentState::HealthCheck(release, time::SystemTime::now())
+        } else {
+            UpdateAgentState::Initialization
+        }
+    }
+
+    /// Stop waiting for a reboot which did not happen in time.
+    ///
+    /// This counts as a failed finalization attempt, so finalization is
+    /// retried until the budget is exhausted.
+    fn reboot_overdue(&mut self, release: libcincinnati::Release, elapsed: time::Duration) {
+        let err = format_err!("no reboot after {}s", elapsed.as_secs());
+        self.finalization_failed(release, err);
+    }
+
+    /// Wait for the reboot triggered by finalization.
+    ///
+    /// Once the release is booted without the agent restarting (e.g. with
+    /// the mock backend, which simulates the reboot), the update outcome is
+    /// recorded as on start. Overdue reboots count as failed finalizations.
+    fn await_reboot(
+        &self,
+        release: libcincinnati::Release,
+        since: time::SystemTime,
+    ) -> ResponseActFuture<Self, (), Error> {
+        let status = actix::fut::wrap_future::<_, Self>(rpm_ostree_status());
+        let rebooted = status.map(move |status, actor, _ctx| {
+            if status.booted.matches_release(&release) {
+                let outcome = reboot_outcome(&status, &release, persist::epoch_secs(since));
+                actor.state = actor.rebooted(release, outcome);
+                return;
+            }
+
+            let elapsed = since.elapsed().unwrap_or_default();
+            if elapsed.as_secs() >= REBOOT_OVERDUE_SECS {
+                actor.reboot_overdue(release, elapsed);
+            } else {
+                debug!(
+                    "reboot into '{}' pending since {}s",
+                    release.version(),
+                    elapsed.as_secs()
+                );
+            }
+        });
+
+        Box::new(rebooted)
+    }
+}
+
+/// Outcome of an update check.
+enum StageOutcome {
+    /// This release was staged.
+    Staged(libcincinnati::Release),
+    /// No update available.
+    NoUpdate,
+    /// Staging this release failed.
+    Failed(libcincinnati::Release, Error),
+}
+
+/// Check for the next update from the booted version, and stage it.
+fn stage_next_update(
+    stream: String,
+    booted_version: String,
+) -> Box<dyn Future<Item = StageOutcome, Error = Error>> {
+    let stage = cincinnati_check_update(stream, booted_version).and_then(|next| match next {
+        Some(release) => future::Either::A(stage_release(release, None)),
+        None => future::Either::B(future::ok(StageOutcome::NoUpdate)),
+    });
+    Box::new(stage)
+}
+
+/// Find the entry release of a new stream, and stage a rebase to it.
+///
+/// The entry release is the greatest one in the target stream, which
+/// may be older than the booted one; rebases are thus allowed to
+/// downgrade.
+fn stage_stream_rebase(
+    booted: rpm_ostree::Deployment,
+    stream: String,
+) -> Box<dyn Future<Item = StageOutcome, Error = Error>> {
+    let refspec = match booted.refspec_for_stream(&stream) {
+        Ok(r) => r,
+        Err(e) => return Box::new(future::err(e)),
+    };
+
+    let stage =
+        cincinnati_stream_entry(stream, booted.version).and_then(move |entry| match entry {
+            Some(release) => {
+                info!(
+                    "rebasing to '{}' at release '{}'",
+                    refspec,
+                    release.version()
+                );
+                future::Either::A(stage_release(release, Some(refspec)))
+            }
+            None => future::Either::B(future::ok(StageOutcome::NoUpdate)),
+        });
+    Box::new(stage)
+}
+
+/// Re-evaluate a staged release against the current graph.
+///
+/// If the staged release is not offered anymore, its deployment is
+/// discarded. If a strictly newer release is available, it is staged
+/// in place of the current one. Failing to fetch the graph keeps the
+/// staged release, so that it does not hold back finalization.
+fn refresh_staged(
+    staged: libcincinnati::Release,
+    configured: Option<String>,
+) -> Box<dyn Future<Item = FinalizeOutcome, Error = Error>> {
+    let refresh = rpm_ostree_status().and_then(move |status| {
+        // Rebases are not re-evaluated, the stream entry does not move.
+        let current = status.booted.stream().map(String::from);
+        let stream = match (configured, current) {
+            (Some(ref stream), Some(ref current)) if stream != current => None,
+            (_, Some(current)) => Some(current),
+            (stream, None) => stream,
+        };
+        let stream = match stream {
+            Some(s) => s,
+            None => return future::Either::A(future::ok(FinalizeOutcome::Waiting)),
+        };
+
+        let booted_version = status.booted.version.clone();
+        let version = staged.version().to_string();
+        let refresh = cincinnati_refresh_staged(stream, booted_version, version).or_else(|e| {
+            warn!("failed to re-evaluate staged release: {}", e);
+            Ok::<_, Error>(cincinnati::StagedRefresh::Current)
+        });
+        let restage = refresh.and_then(move |refresh| match refresh {
+            cincinnati::StagedRefresh::Current => {
+                future::Either::A(future::ok(FinalizeOutcome::Waiting))
+            }
+            cincinnati::StagedRefresh::Superseded(r) => {
+                info!(
+                    "newer release '{}' available, replacing staged '{}'",
+                    r.version(),
+                    staged.version()
+                );
+                let stage = rpm_ostree_cleanup().and_then(|_| stage_release(r, None));
+                future::Either::B(future::Either::A(stage.map(FinalizeOutcome::Restaged)))
+            }
+            cincinnati::StagedRefresh::Pulled => {
+                warn!(
+                    "staged release '{}' is not offered anymore, discarding",
+                    staged.version()
+                );
+                let cleanup = rpm_ostree_cleanup().map(|_| StageOutcome::NoUpdate);
+                future::Either::B(future::Either::B(cleanup.map(FinalizeOutcome::Restaged)))
+            }
+        });
+        future::Either::B(restage)
+    });
+    Box::new(refresh)
+}
+
+/// Stage a release, turning rpm-ostree failures into an outcome.
+fn stage_release(
+    release: libcincinnati::Release,
+    refspec: Option<String>,
+) -> impl Future<Item = StageOutcome, Error = Error> {
+    rpm_ostree_stage(release.clone(), refspec).then(|res| match res {
+        Ok(Some(r)) => Ok(StageOutcome::Staged(r)),
+        Ok(None) => Ok(StageOutcome::NoUpdate),
+        Err(e) => Ok(StageOutcome::Failed(release, e)),
+    })
+}
+
+/// Outcome of crash-recovery on initialization.
+#[derive(Debug)]
+enum Recovery {
+    /// Nothing to recover.
+    Fresh,
+    /// A deployment for this release is staged.
+    Staged(libcincinnati::Release),
+    /// Staging this release failed, after the given number of attempts.
+    StagingFailed(libcincinnati::Release, u32),
+    /// Finalizing this release failed, after the given number of attempts.
+    FinalizationFailed(libcincinnati::Release, u32),
+    /// A deployment for this release is staged and already finalized.
+    Finalized(libcincinnati::Release),
+    /// Rebooted after finalizing this release.
+    Rebooted(libcincinnati::Release, UpdateOutcome),
+    /// Health checks for this release were in progress since the given time.
+    HealthCheck(libcincinnati::Release, time::SystemTime),
+    /// This release failed health checks, and is being rolled back.
+    RollbackPending(libcincinnati::Release),
+    /// This release was previously abandoned, for the given reason.
+    Abandoned(libcincinnati::Release, String),
+}
+
+/// Reconcile persisted state against actual deployments.
+///
+/// Staged deployments are matched against the configured stream, or
+/// the booted one if none is configured.
+fn reconcile(
+    status: rpm_ostree::DeploymentStatus,
+    persisted: Option<PersistedState>,
+    configured: Option<String>,
+) -> Box<dyn Future<Item = Recovery, Error = Error>> {
+    let boot_id = persist::current_boot_id();
+    match reconcile_persisted(&status, persisted, boot_id.as_deref()) {
+        Some(recovery) => Box::new(future::ok(recovery)),
+        None => {
+            let stream = configured.or_else(|| status.booted.stream().map(String::from));
+            recover_from_status(status, stream)
+        }
+    }
+}
+
+/// Resume from persisted state, if it is consistent with actual deployments.
+///
+/// A pending reboot is only reported as failed if the node actually
+/// rebooted, i.e. the current boot ID differs from the persisted one.
+/// Returns `None` if recovery has to rely on actual deployments only.
+fn reconcile_persisted(
+    status: &rpm_ostree::DeploymentStatus,
+    persisted: Option<PersistedState>,
+    boot_id: Option<&str>,
+) -> Option<Recovery> {
+    let reason = persisted.as_ref().and_then(|p| p.reason.clone());
+    let attempts = persisted.as_ref().and_then(|p| p.attempts).unwrap_or(1);
+    let rebooted = match (persisted.as_ref().and_then(|p| p.boot_id.as_ref()), boot_id) {
+        (Some(before), Some(now)) => before != now,
+        _ => false,
+    };
+    let (state, target, since) = persisted.and_then(|p| {
+        let target = p.target.as_ref()?.to_release();
+        Some((p.state, target, p.state_since))
+    })?;
+    let staged_target = match status.staged {
+        Some(ref d) if d.matches_release(&target) => Some(d.finalization_locked),
+        _ => None,
+    };
+
+    let recovery = match state.as_str() {
+        "reboot-pending" => match staged_target {
+            // Agent restarted before the deployment was finalized.
+            Some(true) => Recovery::Staged(target),
+            // Agent restarted, but the reboot did not happen yet.
+            Some(false) => Recovery::Finalized(target),
+            None if rebooted || status.booted.matches_release(&target) => {
+                let outcome = reboot_outcome(status, &target, since);
+                Recovery::Rebooted(target, outcome)
+            }
+            // Same boot, and the finalized deployment is gone.
+            None => return None,
+        },
+        "health-check" => {
+            if !status.booted.matches_release(&target) {
+                return None;
+            }
+            let since = time::UNIX_EPOCH + time::Duration::from_secs(since);
+            Recovery::HealthCheck(target, since)
+        }
+        "rollback-pending" => {
+            if !status.booted.matches_release(&target) {
+                info!("rolled back from update '{}'", target.version());
+                return None;
+            }
+            Recovery::RollbackPending(target)
+        }
+        "update-staged" => match staged_target {
+            Some(true) => Recovery::Staged(target),
+            Some(false) => Recovery::Finalized(target),
+            None => return None,
+        },
+        "staging-failed" => Recovery::StagingFailed(target, attempts),
+        "finalization-failed" => match staged_target {
+            Some(true) => Recovery::FinalizationFailed(target, attempts),
+            Some(false) => Recovery::Finalized(target),
+            None => return None,
+        },
+        "abandoned" => {
+            let reason = reason.unwrap_or_else(|| "unknown reason".to_string());
+            Recovery::Abandoned(target, reason)
+        }
+        _ => return None,
+    };
+    Some(recovery)
+}
+
+/// Outcome of an update finalized at the given time, after rebooting.
+fn reboot_outcome(
+    status: &rpm_ostree::DeploymentStatus,
+    target: &libcincinnati::Release,
+    finalized_at: u64,
+) -> UpdateOutcome {
+    UpdateOutcome {
+        target_version: target.version().to_string(),
+        booted_version: status.booted.version.clone(),
+        success: status.booted.matches_release(target),
+        finalized_at,
+        verified_at: persist::epoch_secs(time::SystemTime::now()),
+    }
+}
+
+/// Recover from actual deployments only.
+fn recover_from_status(
+    status: rpm_ostree::DeploymentStatus,
+    stream: Option<String>,
+) -> Box<dyn Future<Item = Recovery, Error = Error>> {
+    match status.staged {
+        Some(staged) => recover_staged(staged, stream),
+        None => Box::new(future::ok(Recovery::Fresh)),
+    }
+}
+
+/// Match a staged deployment to a release in the current stream.
+///
+/// Deployments which do not match (other stream, release not in graph,
+/// different payload) are cleaned up.
+fn recover_staged(
+    staged: rpm_ostree::Deployment,
+    stream: Option<String>,
+) -> Box<dyn Future<Item = Recovery, Error = Error>> {
+    info!(
+        "found staged deployment '{}' (version '{}')",
+        staged.checksum, staged.version
+    );
+    let stream = match stream {
+        Some(ref s) if staged.stream() == Some(s.as_str()) => s.clone(),
+        _ => {
+            warn!("staged deployment is not on the current stream, discarding");
+            return Box::new(rpm_ostree_cleanup().map(|_| Recovery::Fresh));
+        }
+    };
+
+    let recovery =
+        cincinnati_find_release(staged.version.clone(), stream).and_then(move |release| {
+            match release {
+                Some(ref r) if staged.matches_release(r) => {
+                    let r = r.clone();
+                    let recovery = if staged.finalization_locked {
+                        Recovery::Staged(r)
+                    } else {
+                        Recovery::Finalized(r)
+                    };
+                    future::Either::A(future::ok(recovery))
+                }
+                _ => {
+                    warn!("staged deployment does not match any known release, discarding");
+                    future::Either::B(rpm_ostree_cleanup().map(|_| Recovery::Fresh))
+                }
+            }
+        });
+    Box::new(recovery)
+}
+
+/// Outcome of a finalization attempt.
+enum FinalizeOutcome {
+    /// Finalization allowed, not yet requested.
+    Ready(libcincinnati::Release),
+    /// Finalization requested to rpm-ostree.
+    Finalized(Option<libcincinnati::Release>),
+    /// Finalization not allowed at this time.
+    Waiting,
+    /// Staged deployment does not match the release payload.
+    Mismatch(libcincinnati::Release),
+    /// Finalization of this release failed.
+    Failed(libcincinnati::Release, Error),
+    /// Staged release was superseded, and replaced.
+    Restaged(StageOutcome),
+}
+
+fn rpm_ostree_verify_staged(
+    release: libcincinnati::Release,
+) -> impl Future<Item = bool, Error = Error> {
+    let addr = System::current()
+        .registry()
+        .get::<rpm_ostree::RpmOstreeClient>();
+    let req = rpm_ostree::VerifyStagedPayload { release };
+    addr.send(req)
+        .flatten()
+        .from_err()
+        .map_err(|e| Subsystem::RpmOstree.tag(e))
+}
+
+fn rpm_ostree_status() -> impl Future<Item = rpm_ostree::DeploymentStatus, Error = Error> {
+    let addr = System::current()
+        .registry()
+        .get::<rpm_ostree::RpmOstreeClient>();
+    let req = rpm_ostree::QueryStatus {};
+    addr.send(req)
+        .flatten()
+        .from_err()
+        .map_err(|e| Subsystem::RpmOstree.tag(e))
 }
 
 fn rpm_ostree_stage(
     release: libcincinnati::Release,
+    refspec: Option<String>,
 ) -> impl Future<Item = Option<libcincinnati::Release>, Error = Error> {
     let addr = System::current()
         .registry()
         .get::<rpm_ostree::RpmOstreeClient>();
-    let req = rpm_ostree::StageUpdate { release };
+    let req = rpm_ostree::StageUpdate {
+        release,
+        refspec,
+        progress: Some(progress_recipient()),
+    };
     addr.send(req).flatten().from_err()
 }
 
@@ -174,14 +1104,392 @@ fn rpm_ostree_finalize(
     let addr = System::current()
         .registry()
         .get::<rpm_ostree::RpmOstreeClient>();
-    let req = rpm_ostree::FinalizeUpdate { release };
+    let req = rpm_ostree::FinalizeUpdate {
+        release,
+        progress: Some(progress_recipient()),
+    };
     addr.send(req).flatten().from_err()
 }
 
-fn cincinnati_check_update() -> impl Future<Item = Option<libcincinnati::Release>, Error = Error> {
+/// Recipient for rpm-ostree transaction progress.
+fn progress_recipient() -> Recipient<rpm_ostree::TransactionProgress> {
+    System::current()
+        .registry()
+        .get::<UpdateAgent>()
+        .recipient()
+}
+
+fn rpm_ostree_rollback() -> impl Future<Item = (), Error = Error> {
     let addr = System::current()
         .registry()
-        .get::<cincinnati::CincinnatiClient>();
-    let req = cincinnati::FetchGraph {};
+        .get::<rpm_ostree::RpmOstreeClient>();
+    let req = rpm_ostree::RollbackUpdate { reboot: true };
+    addr.send(req)
+        .flatten()
+        .from_err()
+        .map_err(|e| Subsystem::RpmOstree.tag(e))
+}
+
+fn health_run_checks(
+    since: time::SystemTime,
+) -> impl Future<Item = health::HealthReport, Error = Error> {
+    let addr = System::current().registry().get::<health::HealthChecker>();
+    let req = health::RunChecks { since };
     addr.send(req).flatten().from_err()
 }
+
+fn rpm_ostree_cleanup() -> impl Future<Item = (), Error = Error> {
+    let addr = System::current()
+        .registry()
+        .get::<rpm_ostree::RpmOstreeClient>();
+    let req = rpm_ostree::CleanupPending {};
+    addr.send(req)
+        .flatten()
+        .from_err()
+        .map_err(|e| Subsystem::RpmOstree.tag(e))
+}
+
+fn cincinnati_check_update(
+    stream: String,
+    current: String,
+) -> impl Future<Item = Option<libcincinnati::Release>, Error = Error> {
+    let addr = System::current()
+        .registry()
+        .get::<cincinnati::CincinnatiClient>();
+    let req = cincinnati::FetchGraph { stream, current };
+    addr.send(req)
+        .flatten()
+        .from_err()
+        .map_err(|e| Subsystem::Cincinnati.tag(e))
+}
+
+fn cincinnati_refresh_staged(
+    stream: String,
+    current: String,
+    staged: String,
+) -> impl Future<Item = cincinnati::StagedRefresh, Error = Error> {
+    let addr = System::current()
+        .registry()
+        .get::<cincinnati::CincinnatiClient>();
+    let req = cincinnati::RefreshStaged {
+        stream,
+        current,
+        staged,
+    };
+    addr.send(req)
+        .flatten()
+        .from_err()
+        .map_err(|e| Subsystem::Cincinnati.tag(e))
+}
+
+fn cincinnati_stream_entry(
+    stream: String,
+    current: String,
+) -> impl Future<Item = Option<libcincinnati::Release>, Error = Error> {
+    let addr = System::current()
+        .registry()
+        .get::<cincinnati::CincinnatiClient>();
+    let req = cincinnati::FetchStreamEntry { stream, current };
+    addr.send(req)
+        .flatten()
+        .from_err()
+        .map_err(|e| Subsystem::Cincinnati.tag(e))
+}
+
+fn cincinnati_find_release(
+    version: String,
+    stream: String,
+) -> impl Future<Item = Option<libcincinnati::Release>, Error = Error> {
+    let addr = System::current()
+        .registry()
+        .get::<cincinnati::CincinnatiClient>();
+    let req = cincinnati::FindRelease { version, stream };
+    addr.send(req)
+        .flatten()
+        .from_err()
+        .map_err(|e| Subsystem::Cincinnati.tag(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AgentInput;
+    use crate::testing::release;
+
+    fn deployment(
+        version: &str,
+        commit: char,
+        booted: bool,
+        locked: bool,
+    ) -> rpm_ostree::Deployment {
+        rpm_ostree::Deployment {
+            id: format!("deploy-{}", version),
+            version: version.to_string(),
+            checksum: commit.to_string().repeat(64),
+            base_checksum: None,
+            container_digest: None,
+            origin: "fedora:fedora/x86_64/coreos/testing".to_string(),
+            timestamp: 0,
+            pinned: false,
+            layered_packages: vec![],
+            booted,
+            staged: !booted,
+            finalization_locked: locked,
+        }
+    }
+
+    /// Boot ID of persisted states.
+    static BOOT_ID: &str = "3e7a5e4c-4b8f-4a43-9d3c-1a3c0f0d2b6e";
+
+    fn persisted(state: &str, target: &libcincinnati::Release) -> PersistedState {
+        PersistedState {
+            state: state.to_string(),
+            target: PersistedRelease::from_release(target),
+            state_since: 1000,
+            failures: Default::default(),
+            lock: None,
+            last_update: None,
+            last_update_reported: false,
+            paused: false,
+            reason: Some("bad release".to_string()),
+            attempts: Some(2),
+            boot_id: Some(BOOT_ID.to_string()),
+        }
+    }
+
+    /// Summary of a recovery outcome, for comparisons.
+    fn summary(recovery: Option<Recovery>) -> String {
+        match recovery {
+            None => "status".to_string(),
+            Some(Recovery::Fresh) => "fresh".to_string(),
+            Some(Recovery::Staged(r)) => format!("staged {}", r.version()),
+            Some(Recovery::StagingFailed(r, n)) => {
+                format!("staging-failed {} attempts={}", r.version(), n)
+            }
+            Some(Recovery::FinalizationFailed(r, n)) => {
+                format!("finalization-failed {} attempts={}", r.version(), n)
+            }
+            Some(Recovery::Finalized(r)) => format!("finalized {}", r.version()),
+            Some(Recovery::Rebooted(r, outcome)) => {
+                assert_eq!(outcome.target_version, r.version());
+                assert_eq!(outcome.finalized_at, 1000);
+                format!("rebooted {} success={}", r.version(), outcome.success)
+            }
+            Some(Recovery::HealthCheck(r, since)) => {
+                assert_eq!(since, time::UNIX_EPOCH + time::Duration::from_secs(1000));
+                format!("health-check {}", r.version())
+            }
+            Some(Recovery::RollbackPending(r)) => format!("rollback-pending {}", r.version()),
+            Some(Recovery::Abandoned(r, reason)) => {
+                format!("abandoned {}: {}", r.version(), reason)
+            }
+        }
+    }
+
+    /// Agent in the given state, with its state and blocklist files in `dir`.
+    fn agent(dir: &tempfile::TempDir, state: UpdateAgentState) -> UpdateAgent {
+        let path = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
+        let cfg = AgentInput {
+            state_file: path("state.json"),
+            blocklist_file: path("blocklist.json"),
+            ..AgentInput::default()
+        };
+        UpdateAgent {
+            identity: Identity {
+                arch: "amd64".to_string(),
+                current_version: "30.1".to_string(),
+                group: "default".to_string(),
+                node_uuid: uuid::Uuid::nil(),
+                platform: "metal-bios".to_string(),
+                stream: None,
+                booted_stream: None,
+                throttle_permille: None,
+            },
+            scheduler: Scheduler::try_from_config(&cfg).unwrap(),
+            strategy: strategy::UpStrategy::default(),
+            state,
+            in_flight: None,
+            state_file: StateFile::from_config(&cfg),
+            state_since: time::SystemTime::now(),
+            lock: None,
+            last_update: None,
+            last_update_reported: false,
+            blocklist: Blocklist::from_config(&cfg),
+            paused: false,
+            force_finalize: false,
+            next_tick: None,
+            booted_version: None,
+            last_check: None,
+            metrics_textfile: None,
+            status_watch: StatusWatch::default(),
+        }
+    }
+
+    #[test]
+    fn test_reboot_overdue() {
+        let tmp = tempfile::tempdir().unwrap();
+        let target = release("30.2", 'b');
+        let overdue = time::Duration::from_secs(REBOOT_OVERDUE_SECS);
+        let since = time::SystemTime::now() - overdue;
+
+        // An overdue reboot counts as a failed finalization attempt.
+        let pending = UpdateAgentState::RebootPending(target.clone(), since, 0);
+        let mut agent = agent(&tmp, pending);
+        agent.reboot_overdue(target.clone(), overdue);
+        match agent.state {
+            UpdateAgentState::FinalizationFailed(ref r, 1) => assert_eq!(r.version(), "30.2"),
+            ref state => panic!("unexpected state {:?}", state),
+        };
+
+        // Once out of budget (3 attempts by default), the release is abandoned.
+        agent.state = UpdateAgentState::RebootPending(target.clone(), since, 2);
+        agent.reboot_overdue(target.clone(), overdue);
+        match agent.state {
+            UpdateAgentState::Abandoned(ref r, _) => assert_eq!(r.version(), "30.2"),
+            ref state => panic!("unexpected state {:?}", state),
+        };
+        assert!(agent.blocklist.versions().unwrap().contains("30.2"));
+    }
+
+    #[test]
+    fn test_reconcile_persisted() {
+        let target = release("30.2", 'b');
+
+        // Deployment statuses, as seen on start.
+        let old = deployment("30.1", 'a', true, false);
+        let statuses = vec![
+            ("not staged", old.clone(), None),
+            (
+                "staged",
+                old.clone(),
+                Some(deployment("30.2", 'b', false, true)),
+            ),
+            (
+                "finalized",
+                old.clone(),
+                Some(deployment("30.2", 'b', false, false)),
+            ),
+            ("rebooted", deployment("30.2", 'b', true, false), None),
+            (
+                "staged other",
+                old.clone(),
+                Some(deployment("30.3", 'c', false, true)),
+            ),
+        ];
+
+        // Expected outcome for each persisted state, per deployment status.
+        let cases = vec![
+            (
+                "reboot-pending",
+                vec![
+                    "status",
+                    "staged 30.2",
+                    "finalized 30.2",
+                    "rebooted 30.2 success=true",
+                    "status",
+                ],
+            ),
+            (
+                "health-check",
+                vec!["status", "status", "status", "health-check 30.2", "status"],
+            ),
+            (
+                "rollback-pending",
+                vec![
+                    "status",
+                    "status",
+                    "status",
+                    "rollback-pending 30.2",
+                    "status",
+                ],
+            ),
+            (
+                "update-staged",
+                vec![
+                    "status",
+                    "staged 30.2",
+                    "finalized 30.2",
+                    "status",
+                    "status",
+                ],
+            ),
+            ("staging-failed", vec!["staging-failed 30.2 attempts=2"; 5]),
+            (
+                "finalization-failed",
+                vec![
+                    "status",
+                    "finalization-failed 30.2 attempts=2",
+                    "finalized 30.2",
+                    "status",
+                    "status",
+                ],
+            ),
+            ("abandoned", vec!["abandoned 30.2: bad release"; 5]),
+            ("no-new-update", vec!["status"; 5]),
+        ];
+
+        for (state, expected) in cases {
+            for ((name, booted, staged), expected) in statuses.iter().zip(expected) {
+                let status = rpm_ostree::DeploymentStatus {
+                    booted: booted.clone(),
+                    staged: staged.clone(),
+                    pending: None,
+                    rollback: None,
+                };
+                let recovery =
+                    reconcile_persisted(&status, Some(persisted(state, &target)), Some(BOOT_ID));
+                assert_eq!(
+                    summary(recovery),
+                    expected,
+                    "state '{}', deployments '{}'",
+                    state,
+                    name
+                );
+            }
+        }
+
+        // After an actual reboot, a pending reboot into another deployment failed.
+        let expected = vec![
+            "rebooted 30.2 success=false",
+            "staged 30.2",
+            "finalized 30.2",
+            "rebooted 30.2 success=true",
+            "rebooted 30.2 success=false",
+        ];
+        for ((name, booted, staged), expected) in statuses.iter().zip(expected) {
+            let status = rpm_ostree::DeploymentStatus {
+                booted: booted.clone(),
+                staged: staged.clone(),
+                pending: None,
+                rollback: None,
+            };
+            let state = persisted("reboot-pending", &target);
+            let recovery = reconcile_persisted(&status, Some(state), Some("other-boot"));
+            assert_eq!(summary(recovery), expected, "deployments '{}'", name);
+        }
+
+        // Without persisted state (or target), recovery relies on deployments only.
+        for (_, booted, staged) in &statuses {
+            let status = rpm_ostree::DeploymentStatus {
+                booted: booted.clone(),
+                staged: staged.clone(),
+                pending: None,
+                rollback: None,
+            };
+            assert_eq!(
+                summary(reconcile_persisted(&status, None, Some(BOOT_ID))),
+                "status"
+            );
+            let mut untargeted = persisted("reboot-pending", &target);
+            untargeted.target = None;
+            assert_eq!(
+                summary(reconcile_persisted(
+                    &status,
+                    Some(untargeted),
+                    Some(BOOT_ID)
+                )),
+                "status"
+            );
+        }
+    }
+}
@@ -0,0 +1,227 @@
+//! Local blocklist of bad releases.
+//!
+//! Releases whose update failed (staging error, failed health checks,
+//! reboot back into the previous deployment) are recorded here, and are
+//! not offered again by the Cincinnati client until removed.
+
+use super::persist;
+use crate::config::AgentInput;
+use failure::{Fallible, ResultExt};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time;
+
+/// Default location of the blocklist file.
+static DEFAULT_BLOCKLIST_FILE: &str = "/var/lib/zincati/blocklist.json";
+
+/// Blocklist file on disk.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct Blocklist {
+    path: PathBuf,
+}
+
+/// A blocked release.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct BlockedRelease {
+    pub(crate) version: String,
+    /// Why the release was blocked.
+    pub(crate) reason: String,
+    /// Time the release was blocked, in seconds since UNIX epoch.
+    pub(crate) blocked_at: u64,
+}
+
+/// Blocklist content, as persisted on disk.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct BlocklistContent {
+    releases: Vec<BlockedRelease>,
+}
+
+impl Blocklist {
+    /// Validate inputs and return the blocklist location.
+    pub(crate) fn from_config(cfg: &AgentInput) -> Self {
+        let path = if cfg.blocklist_file.is_empty() {
+            DEFAULT_BLOCKLIST_FILE
+        } else {
+            &cfg.blocklist_file
+        };
+        Self {
+            path: PathBuf::from(path),
+        }
+    }
+
+    /// All blocked releases, oldest first.
+    pub(crate) fn list(&self) -> Fallible<Vec<BlockedRelease>> {
+        Ok(self.load()?.releases)
+    }
+
+    /// Versions of all blocked releases.
+    pub(crate) fn versions(&self) -> Fallible<BTreeSet<String>> {
+        let versions = self
+            .list()?
+            .into_iter()
+            .map(|entry| entry.version)
+            .collect();
+        Ok(versions)
+    }
+
+    /// Block a release, replacing any previous entry for it.
+    pub(crate) fn add(&self, version: &str, reason: &str) -> Fallible<()> {
+        let mut content = self.load()?;
+        content.releases.retain(|entry| entry.version != version);
+        content.releases.push(BlockedRelease {
+            version: version.to_string(),
+            reason: reason.to_string(),
+            blocked_at: persist::epoch_secs(time::SystemTime::now()),
+        });
+        persist::write_atomic(&self.path, &content)?;
+
+        warn!("release '{}' blocklisted: {}", version, reason);
+        Ok(())
+    }
+
+    /// Unblock a release, returning whether it was blocked.
+    pub(crate) fn remove(&self, version: &str) -> Fallible<bool> {
+        let mut content = self.load()?;
+        let before = content.releases.len();
+        content.releases.retain(|entry| entry.version != version);
+        if content.releases.len() == before {
+            return Ok(false);
+        }
+        persist::write_atomic(&self.path, &content)?;
+
+        info!("release '{}' removed from blocklist", version);
+        Ok(true)
+    }
+
+    /// Load blocklist content, empty if missing.
+    fn load(&self) -> Fallible<BlocklistContent> {
+        let content = match fs::read(&self.path) {
+            Ok(c) => c,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(BlocklistContent::default())
+            }
+            Err(e) => {
+                return Err(e)
+                    .context(format!("failed to read '{}'", self.path.display()))
+                    .map_err(Into::into)
+            }
+        };
+        let blocklist = serde_json::from_slice(&content)
+            .context(format!("failed to parse '{}'", self.path.display()))?;
+        Ok(blocklist)
+    }
+}
+
+/// Blocklist subcommands.
+#[derive(Debug, StructOpt)]
+pub(crate) enum BlocklistCommand {
+    /// List blocked releases
+    #[structopt(name = "list")]
+    List,
+    /// Remove a release from the blocklist
+    #[structopt(name = "remove")]
+    Remove {
+        /// Release version
+        version: String,
+    },
+}
+
+/// Run a blocklist subcommand.
+pub(crate) fn run_blocklist_cli(blocklist: &Blocklist, cmd: BlocklistCommand) -> Fallible<()> {
+    match cmd {
+        BlocklistCommand::List => {
+            for entry in blocklist.list()? {
+                println!("{}\t{}\t{}", entry.version, entry.blocked_at, entry.reason);
+            }
+        }
+        BlocklistCommand::Remove { version } => {
+            if !blocklist.remove(&version)? {
+                bail!("release '{}' is not blocklisted", version);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Blocklist in a subdirectory of `dir`, not created yet.
+    fn blocklist(dir: &tempfile::TempDir) -> Blocklist {
+        let dir = dir.path().join("zincati");
+        let cfg = AgentInput {
+            blocklist_file: dir.join("blocklist.json").to_string_lossy().into_owned(),
+            ..AgentInput::default()
+        };
+        Blocklist::from_config(&cfg)
+    }
+
+    #[test]
+    fn test_missing_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let blocklist = blocklist(&tmp);
+        assert!(blocklist.list().unwrap().is_empty());
+        assert!(blocklist.versions().unwrap().is_empty());
+        assert!(!blocklist.remove("30.1").unwrap());
+        assert!(!blocklist.path.exists());
+    }
+
+    #[test]
+    fn test_add_remove_roundtrip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let blocklist = blocklist(&tmp);
+        blocklist.add("30.1", "failed health checks").unwrap();
+        blocklist.add("30.2", "staging failed").unwrap();
+        // Blocking again replaces the previous entry.
+        blocklist.add("30.1", "booted into '30.0'").unwrap();
+
+        // Entries survive a reload from disk, oldest first.
+        let reloaded = Blocklist {
+            path: blocklist.path.clone(),
+        };
+        let entries = reloaded.list().unwrap();
+        let summary: Vec<_> = entries
+            .iter()
+            .map(|e| (e.version.as_str(), e.reason.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![("30.2", "staging failed"), ("30.1", "booted into '30.0'")]
+        );
+        assert!(entries.iter().all(|e| e.blocked_at > 0));
+
+        assert!(reloaded.remove("30.2").unwrap());
+        assert!(!reloaded.remove("30.2").unwrap());
+        let versions: Vec<_> = blocklist.versions().unwrap().into_iter().collect();
+        assert_eq!(versions, vec!["30.1".to_string()]);
+    }
+
+    #[test]
+    fn test_malformed_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let blocklist = blocklist(&tmp);
+        std::fs::create_dir_all(blocklist.path.parent().unwrap()).unwrap();
+        std::fs::write(&blocklist.path, "not json").unwrap();
+        blocklist.list().unwrap_err();
+        blocklist.add("30.1", "staging failed").unwrap_err();
+    }
+
+    #[test]
+    fn test_cli_remove() {
+        let tmp = tempfile::tempdir().unwrap();
+        let blocklist = blocklist(&tmp);
+        blocklist.add("30.1", "failed health checks").unwrap();
+
+        run_blocklist_cli(&blocklist, BlocklistCommand::List).unwrap();
+        let remove = |version: &str| BlocklistCommand::Remove {
+            version: version.to_string(),
+        };
+        run_blocklist_cli(&blocklist, remove("30.1")).unwrap();
+        assert!(blocklist.list().unwrap().is_empty());
+        // Removing a release which is not blocked is an error.
+        run_blocklist_cli(&blocklist, remove("30.1")).unwrap_err();
+    }
+}
@@ -0,0 +1,119 @@
+//! Operator requests to the agent.
+//!
+//! These are sent by the local control API, to steer the state-machine
+//! without restarting it.
+
+use super::agent::{RefreshTick, UpdateAgent, UpdateAgentState};
+use actix::prelude::*;
+use failure::{Error, Fallible};
+use std::time;
+
+/// UpdateAgent request: check for updates now.
+pub(crate) struct TriggerCheck {}
+
+impl Message for TriggerCheck {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<TriggerCheck> for UpdateAgent {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, _msg: TriggerCheck, ctx: &mut Self::Context) -> Self::Result {
+        info!("refresh requested by operator");
+        self.refresh_now(ctx)
+    }
+}
+
+/// UpdateAgent request: pause or resume updates.
+pub(crate) struct SetPaused {
+    pub(crate) paused: bool,
+}
+
+impl Message for SetPaused {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<SetPaused> for UpdateAgent {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: SetPaused, _ctx: &mut Self::Context) -> Self::Result {
+        if self.paused != msg.paused {
+            self.paused = msg.paused;
+            self.persist_state();
+            self.publish_status();
+        }
+        if msg.paused {
+            warn!("updates paused by operator");
+        } else {
+            info!("updates resumed by operator");
+        }
+        Ok(())
+    }
+}
+
+/// UpdateAgent request: finalize the staged update now, bypassing the strategy.
+pub(crate) struct ForceFinalize {}
+
+impl Message for ForceFinalize {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<ForceFinalize> for UpdateAgent {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, _msg: ForceFinalize, ctx: &mut Self::Context) -> Self::Result {
+        match self.state {
+            UpdateAgentState::UpdateStaged(ref r)
+            | UpdateAgentState::FinalizationFailed(ref r, _) => {
+                warn!("finalization of '{}' forced by operator", r.version());
+            }
+            _ => bail!("no staged update to finalize"),
+        };
+        // The tick is only delivered after this handler returns, so the
+        // flag is set once the refresh has actually been scheduled.
+        self.refresh_now(ctx)?;
+        self.force_finalize = true;
+        Ok(())
+    }
+}
+
+/// UpdateAgent request: clear an abandoned update and start over.
+///
+/// The abandoned release stays blocklisted.
+pub(crate) struct ClearAbandoned {}
+
+impl Message for ClearAbandoned {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<ClearAbandoned> for UpdateAgent {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, _msg: ClearAbandoned, ctx: &mut Self::Context) -> Self::Result {
+        match self.state {
+            UpdateAgentState::Abandoned(ref r, _) => {
+                info!("abandoned update '{}' cleared by operator", r.version());
+            }
+            _ => bail!("no abandoned update"),
+        };
+        self.state = UpdateAgentState::Initialization;
+        self.state_since = time::SystemTime::now();
+        self.persist_state();
+        self.publish_status();
+        self.refresh_now(ctx)
+    }
+}
+
+impl UpdateAgent {
+    /// Run a refresh now, instead of waiting for the next scheduled one.
+    fn refresh_now(&mut self, ctx: &mut Context<Self>) -> Fallible<()> {
+        if let Some(ref op) = self.in_flight {
+            bail!("operation '{}' already in progress", op.operation);
+        }
+        if let Some(handle) = self.next_tick.take() {
+            ctx.cancel_future(handle);
+        }
+        ctx.notify(RefreshTick {});
+        Ok(())
+    }
+}
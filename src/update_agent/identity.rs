@@ -1,4 +1,5 @@
 use crate::config::IdentityInput;
+use crate::http;
 use failure::{Fallible, ResultExt};
 use uuid::Uuid;
 
@@ -12,7 +13,12 @@ pub(crate) struct Identity {
     pub(crate) group: String,
     pub(crate) node_uuid: Uuid,
     pub(crate) platform: String,
-    pub(crate) stream: String,
+    /// Explicitly configured update stream, if any.
+    ///
+    /// When unset, the agent follows the stream of the booted deployment.
+    pub(crate) stream: Option<String>,
+    /// Stream of the booted deployment, if known at startup.
+    pub(crate) booted_stream: Option<String>,
     /// Throttle level, 0 (never) to 1000 (unlimited).
     pub(crate) throttle_permille: Option<u16>,
 }
@@ -31,9 +37,14 @@ impl Identity {
             Uuid::parse_str(&cfg.node_uuid).context("failed to parse uuid")?
         };
 
+        let stream = if cfg.stream.is_empty() {
+            None
+        } else {
+            Some(cfg.stream)
+        };
+
         // TODO(lucab): populate these.
         let arch = String::from("amd64");
-        let stream = String::from("stable");
         let platform = String::from("metal-bios");
         let throttle_permille = if cfg.throttle_permille.is_empty() {
             None
@@ -45,6 +56,7 @@ impl Identity {
         let identity = Self {
             arch,
             stream,
+            booted_stream: None,
             platform,
             current_version,
             group,
@@ -53,6 +65,18 @@ impl Identity {
         };
         Ok(identity)
     }
+
+    /// User-Agent for outbound requests, reporting the stream this node is on.
+    ///
+    /// This is the booted stream if known, otherwise the configured one.
+    pub(crate) fn user_agent(&self) -> String {
+        let stream = self
+            .booted_stream
+            .as_ref()
+            .or(self.stream.as_ref())
+            .map_or("unknown", String::as_str);
+        http::user_agent(stream)
+    }
 }
 
 fn read_os_release() -> Fallible<String> {
@@ -2,18 +2,50 @@
 
 mod identity;
 mod agent;
+mod blocklist;
+mod control;
+mod persist;
+mod schedule;
+mod status;
 
 pub(crate) use identity::Identity;
-pub(crate) use agent::UpdateAgent;
+pub(crate) use agent::{UpdateAgent, UpdateAgentState};
+pub(crate) use blocklist::{run_blocklist_cli, Blocklist, BlocklistCommand};
+pub(crate) use control::{ClearAbandoned, ForceFinalize, SetPaused, TriggerCheck};
+pub(crate) use persist::StateFile;
+pub(crate) use schedule::{Scheduler, Subsystem};
+pub(crate) use status::{AgentStatus, GetStatus, StatusWatch, UpdateOutcome};
 
 use crate::strategy;
 
-pub(crate) fn configure(strategy: strategy::UpStrategy, identity: Identity) -> failure::Fallible<()> {
+pub(crate) fn configure(
+    strategy: strategy::UpStrategy,
+    identity: Identity,
+    scheduler: Scheduler,
+    state_file: StateFile,
+    blocklist: Blocklist,
+    metrics_textfile: Option<std::path::PathBuf>,
+    status_watch: StatusWatch,
+) -> failure::Fallible<()> {
     let actor = UpdateAgent {
         identity,
-        refresh_period: std::time::Duration::from_secs(3),
+        scheduler,
         state: agent::UpdateAgentState::StartState,
         strategy,
+        in_flight: None,
+        state_file,
+        state_since: std::time::SystemTime::now(),
+        lock: None,
+        last_update: None,
+        last_update_reported: false,
+        blocklist,
+        paused: false,
+        force_finalize: false,
+        next_tick: None,
+        booted_version: None,
+        last_check: None,
+        metrics_textfile,
+        status_watch,
     };
     let mut static_cfg = agent::CONFIGURED.try_write().unwrap();
     *static_cfg = Some(actor);
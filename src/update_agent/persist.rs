@@ -0,0 +1,179 @@
+//! Persistent agent state.
+//!
+//! The state-machine is saved to disk on every transition, so that it
+//! survives restarts and reboots. Writes are atomic: content goes to a
+//! temporary file first, which is then renamed over the previous state.
+
+use super::schedule::Subsystem;
+use super::status::UpdateOutcome;
+use crate::config::AgentInput;
+use failure::{Fallible, ResultExt};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time;
+
+/// Default location of the state file.
+static DEFAULT_STATE_FILE: &str = "/var/lib/zincati/state.json";
+
+/// Kernel-provided identifier of the current boot.
+static BOOT_ID_PATH: &str = "/proc/sys/kernel/random/boot_id";
+
+/// State file on disk.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct StateFile {
+    path: PathBuf,
+}
+
+/// Agent state, as persisted on disk.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct PersistedState {
+    /// State-machine state label.
+    pub(crate) state: String,
+    /// Target release of the current state, if any.
+    pub(crate) target: Option<PersistedRelease>,
+    /// Time of the last transition, in seconds since UNIX epoch.
+    pub(crate) state_since: u64,
+    /// Consecutive failures per remote.
+    #[serde(default)]
+    pub(crate) failures: HashMap<Subsystem, u32>,
+    /// Finalization lock held by this agent, if any.
+    pub(crate) lock: Option<LockInfo>,
+    /// Outcome of the last update, if any.
+    #[serde(default)]
+    pub(crate) last_update: Option<UpdateOutcome>,
+    /// Whether the update strategy acknowledged the last update outcome.
+    #[serde(default)]
+    pub(crate) last_update_reported: bool,
+    /// Whether updates are paused by an operator.
+    #[serde(default)]
+    pub(crate) paused: bool,
+    /// Why the target release was abandoned, if it was.
+    #[serde(default)]
+    pub(crate) reason: Option<String>,
+    /// Failed attempts for the target release, in retrying states.
+    #[serde(default)]
+    pub(crate) attempts: Option<u32>,
+    /// Identifier of the boot this state was saved in, if known.
+    #[serde(default)]
+    pub(crate) boot_id: Option<String>,
+}
+
+/// Release details, enough to resume from it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct PersistedRelease {
+    pub(crate) version: String,
+    pub(crate) payload: String,
+    #[serde(default)]
+    pub(crate) metadata: HashMap<String, String>,
+}
+
+/// Finalization lock held by this agent.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct LockInfo {
+    /// Strategy which granted the lock.
+    pub(crate) strategy: String,
+    /// Time the lock was granted, in seconds since UNIX epoch.
+    pub(crate) acquired_at: u64,
+}
+
+impl StateFile {
+    /// Validate inputs and return the state file location.
+    pub(crate) fn from_config(cfg: &AgentInput) -> Self {
+        let path = if cfg.state_file.is_empty() {
+            DEFAULT_STATE_FILE
+        } else {
+            &cfg.state_file
+        };
+        Self {
+            path: PathBuf::from(path),
+        }
+    }
+
+    /// Load persisted state, if any.
+    pub(crate) fn load(&self) -> Fallible<Option<PersistedState>> {
+        let content = match fs::read(&self.path) {
+            Ok(c) => c,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e)
+                    .context(format!("failed to read '{}'", self.path.display()))
+                    .map_err(Into::into)
+            }
+        };
+        let state = serde_json::from_slice(&content)
+            .context(format!("failed to parse '{}'", self.path.display()))?;
+        Ok(Some(state))
+    }
+
+    /// Atomically replace persisted state.
+    pub(crate) fn save(&self, state: &PersistedState) -> Fallible<()> {
+        write_atomic(&self.path, state)?;
+        trace!("agent state saved to '{}'", self.path.display());
+        Ok(())
+    }
+}
+
+/// Atomically replace a JSON file.
+pub(crate) fn write_atomic<T: Serialize>(path: &Path, value: &T) -> Fallible<()> {
+    let dir = match path.parent() {
+        Some(d) => d,
+        None => bail!("invalid file path '{}'", path.display()),
+    };
+    fs::create_dir_all(dir).context(format!("failed to create '{}'", dir.display()))?;
+
+    let tmp_path = path.with_extension("json.tmp");
+    {
+        let mut tmp = fs::File::create(&tmp_path)
+            .context(format!("failed to create '{}'", tmp_path.display()))?;
+        serde_json::to_writer_pretty(&mut tmp, value)?;
+        tmp.write_all(b"\n")?;
+        tmp.sync_all()?;
+    }
+    fs::rename(&tmp_path, path).context(format!("failed to write '{}'", path.display()))?;
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+impl PersistedRelease {
+    /// Persistable details of a release, if concrete.
+    pub(crate) fn from_release(release: &libcincinnati::Release) -> Option<Self> {
+        match release {
+            libcincinnati::Release::Concrete(r) => Some(Self {
+                version: r.version.clone(),
+                payload: r.payload.clone(),
+                metadata: r.metadata.clone(),
+            }),
+            libcincinnati::Release::Abstract(_) => None,
+        }
+    }
+
+    /// Rebuild the release.
+    pub(crate) fn to_release(&self) -> libcincinnati::Release {
+        libcincinnati::Release::Concrete(libcincinnati::ConcreteRelease {
+            version: self.version.clone(),
+            payload: self.payload.clone(),
+            metadata: self.metadata.clone(),
+        })
+    }
+}
+
+/// Identifier of the current boot, if known.
+pub(crate) fn current_boot_id() -> Option<String> {
+    match fs::read_to_string(BOOT_ID_PATH) {
+        Ok(id) => Some(id.trim().to_string()),
+        Err(e) => {
+            warn!("failed to read boot ID from '{}': {}", BOOT_ID_PATH, e);
+            None
+        }
+    }
+}
+
+/// Seconds since UNIX epoch.
+pub(crate) fn epoch_secs(t: time::SystemTime) -> u64 {
+    t.duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
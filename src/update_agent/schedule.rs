@@ -0,0 +1,318 @@
+//! Refresh scheduling, with splay and error backoff.
+//!
+//! Ticks are scheduled one at a time, after the previous tick completed.
+//! A random splay is added to every delay, so that a fleet does not poll
+//! remote services in lockstep. Consecutive errors against a remote
+//! service back off the refresh period exponentially, up to a cap, and
+//! any `Retry-After` hint from the remote is honored.
+//!
+//! It also holds the retry budgets for local failures (staging and
+//! finalization), after which a release is abandoned.
+
+use crate::config::AgentInput;
+use crate::http::StatusError;
+use failure::{Error, Fallible};
+use rand::Rng;
+use std::collections::HashMap;
+use std::fmt;
+use std::time;
+
+/// Default refresh period, in seconds.
+static DEFAULT_REFRESH_PERIOD_SECS: u64 = 300;
+
+/// Default cap for error backoff, in seconds.
+static DEFAULT_MAX_BACKOFF_SECS: u64 = 3600;
+
+/// Default staging attempts before abandoning a release.
+static DEFAULT_MAX_STAGING_ATTEMPTS: u32 = 3;
+
+/// Default finalization attempts before abandoning a release.
+static DEFAULT_MAX_FINALIZATION_ATTEMPTS: u32 = 3;
+
+/// Subsystems errors are tagged with, for backoff and metrics.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub(crate) enum Subsystem {
+    Cincinnati,
+    RpmOstree,
+    Strategy,
+}
+
+impl fmt::Display for Subsystem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Subsystem::Cincinnati => write!(f, "cincinnati"),
+            Subsystem::RpmOstree => write!(f, "rpm-ostree"),
+            Subsystem::Strategy => write!(f, "strategy"),
+        }
+    }
+}
+
+impl Subsystem {
+    /// All subsystems.
+    pub(crate) const ALL: [Subsystem; 3] = [
+        Subsystem::Cincinnati,
+        Subsystem::RpmOstree,
+        Subsystem::Strategy,
+    ];
+
+    /// Tag an error as originating from this subsystem.
+    pub(crate) fn tag(self, err: Error) -> Error {
+        err.context(self).into()
+    }
+
+    /// Subsystem an error originates from, if tagged.
+    pub(crate) fn of(err: &Error) -> Option<Self> {
+        err.downcast_ref::<failure::Context<Subsystem>>()
+            .map(|ctx| *ctx.get_context())
+    }
+
+    /// Whether this subsystem is a remote service, subject to backoff.
+    fn is_remote(self) -> bool {
+        match self {
+            Subsystem::Cincinnati | Subsystem::Strategy => true,
+            Subsystem::RpmOstree => false,
+        }
+    }
+}
+
+/// Error state for a single remote.
+#[derive(Clone, Debug, Default)]
+struct RemoteErrors {
+    /// Number of consecutive failures.
+    failures: u32,
+    /// Earliest time for the next request, as requested by the remote.
+    not_before: Option<time::Instant>,
+}
+
+/// Tick scheduler.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct Scheduler {
+    /// Base refresh period.
+    refresh_period: time::Duration,
+    /// Maximum random delay added to each tick.
+    max_splay: time::Duration,
+    /// Cap for error backoff.
+    max_backoff: time::Duration,
+    /// Staging attempts before abandoning a release.
+    max_staging_attempts: u32,
+    /// Finalization attempts before abandoning a release.
+    max_finalization_attempts: u32,
+    /// Per-remote error state.
+    #[serde(skip)]
+    errors: HashMap<Subsystem, RemoteErrors>,
+}
+
+impl Scheduler {
+    /// Validate inputs and return a scheduler.
+    pub(crate) fn try_from_config(cfg: &AgentInput) -> Fallible<Self> {
+        let refresh_period = time::Duration::from_secs(
+            cfg.refresh_period_secs
+                .unwrap_or(DEFAULT_REFRESH_PERIOD_SECS),
+        );
+        if refresh_period.as_secs() == 0 {
+            bail!("refresh period must be at least one second");
+        }
+        // By default, spread ticks over half a refresh period.
+        let max_splay = match cfg.splay_secs {
+            Some(s) => time::Duration::from_secs(s),
+            None => refresh_period / 2,
+        };
+        let max_backoff =
+            time::Duration::from_secs(cfg.max_backoff_secs.unwrap_or(DEFAULT_MAX_BACKOFF_SECS));
+        if max_backoff < refresh_period {
+            bail!("maximum backoff must not be shorter than the refresh period");
+        }
+        let max_staging_attempts = cfg
+            .max_staging_attempts
+            .unwrap_or(DEFAULT_MAX_STAGING_ATTEMPTS);
+        let max_finalization_attempts = cfg
+            .max_finalization_attempts
+            .unwrap_or(DEFAULT_MAX_FINALIZATION_ATTEMPTS);
+        if max_staging_attempts == 0 || max_finalization_attempts == 0 {
+            bail!("retry budgets must allow at least one attempt");
+        }
+
+        let scheduler = Self {
+            refresh_period,
+            max_splay,
+            max_backoff,
+            max_staging_attempts,
+            max_finalization_attempts,
+            errors: HashMap::new(),
+        };
+        Ok(scheduler)
+    }
+
+    /// Record the outcome of a tick.
+    ///
+    /// A successful tick resets all error counters. A failed tick
+    /// increases the counter of the remote which caused it, if any.
+    pub(crate) fn record_outcome(&mut self, outcome: &Result<(), Error>) {
+        let err = match outcome {
+            Ok(_) => {
+                self.errors.clear();
+                return;
+            }
+            Err(e) => e,
+        };
+
+        let remote = match Subsystem::of(err) {
+            Some(s) if s.is_remote() => s,
+            _ => return,
+        };
+        let retry_after = err
+            .iter_chain()
+            .filter_map(|cause| cause.downcast_ref::<StatusError>())
+            .filter_map(|status| status.retry_after)
+            .next();
+
+        let entry = self.errors.entry(remote).or_default();
+        entry.failures = entry.failures.saturating_add(1);
+        entry.not_before = retry_after.map(|delay| time::Instant::now() + delay);
+        warn!("{} error ({} consecutive): {}", remote, entry.failures, err);
+    }
+
+    /// Compute the delay before next tick.
+    pub(crate) fn next_delay(&self) -> time::Duration {
+        let now = time::Instant::now();
+        let mut delay = self.refresh_period;
+
+        for (remote, errs) in &self.errors {
+            // Exponential backoff: period * 2^failures, capped.
+            let factor = 1u32.checked_shl(errs.failures).unwrap_or(u32::MAX);
+            let backoff = self
+                .refresh_period
+                .checked_mul(factor)
+                .unwrap_or(self.max_backoff)
+                .min(self.max_backoff);
+            delay = delay.max(backoff);
+
+            if let Some(not_before) = errs.not_before {
+                if not_before > now {
+                    trace!("{} requested to retry after {:?}", remote, not_before - now);
+                    delay = delay.max(not_before - now);
+                }
+            }
+        }
+
+        delay + self.splay()
+    }
+
+    /// Staging attempts before abandoning a release.
+    pub(crate) fn max_staging_attempts(&self) -> u32 {
+        self.max_staging_attempts
+    }
+
+    /// Finalization attempts before abandoning a release.
+    pub(crate) fn max_finalization_attempts(&self) -> u32 {
+        self.max_finalization_attempts
+    }
+
+    /// Consecutive failures per remote.
+    pub(crate) fn failures(&self) -> HashMap<Subsystem, u32> {
+        self.errors
+            .iter()
+            .map(|(remote, errs)| (*remote, errs.failures))
+            .collect()
+    }
+
+    /// Restore consecutive failures per remote, e.g. after a restart.
+    pub(crate) fn restore_failures(&mut self, failures: &HashMap<Subsystem, u32>) {
+        for (remote, count) in failures {
+            let entry = self.errors.entry(*remote).or_default();
+            entry.failures = *count;
+        }
+    }
+
+    /// Random delay, up to configured maximum splay.
+    fn splay(&self) -> time::Duration {
+        let max_millis =
+            self.max_splay.as_secs() * 1000 + u64::from(self.max_splay.subsec_millis());
+        if max_millis == 0 {
+            return time::Duration::from_secs(0);
+        }
+        let millis = rand::thread_rng().gen_range(0, max_millis);
+        time::Duration::from_millis(millis)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Scheduler with a 10s period, no splay, and backoff capped at 100s.
+    fn scheduler() -> Scheduler {
+        let cfg = AgentInput {
+            refresh_period_secs: Some(10),
+            splay_secs: Some(0),
+            max_backoff_secs: Some(100),
+            ..AgentInput::default()
+        };
+        Scheduler::try_from_config(&cfg).unwrap()
+    }
+
+    fn remote_error(remote: Subsystem) -> Result<(), Error> {
+        Err(remote.tag(format_err!("connection refused")))
+    }
+
+    #[test]
+    fn test_next_delay_backoff() {
+        let mut sched = scheduler();
+        assert_eq!(sched.next_delay(), time::Duration::from_secs(10));
+
+        // Backoff doubles on every consecutive failure, up to the cap.
+        for expected in &[20, 40, 80, 100, 100] {
+            sched.record_outcome(&remote_error(Subsystem::Cincinnati));
+            assert_eq!(sched.next_delay(), time::Duration::from_secs(*expected));
+        }
+
+        // The cap holds even when the exponent overflows.
+        let failures = vec![(Subsystem::Strategy, 64)].into_iter().collect();
+        sched.restore_failures(&failures);
+        assert_eq!(sched.next_delay(), time::Duration::from_secs(100));
+
+        // Local errors are not accounted, success resets all remotes.
+        sched.record_outcome(&Err(format_err!("local failure")));
+        sched.record_outcome(&remote_error(Subsystem::RpmOstree));
+        assert_eq!(sched.failures()[&Subsystem::Cincinnati], 5);
+        sched.record_outcome(&Ok(()));
+        assert!(sched.failures().is_empty());
+        assert_eq!(sched.next_delay(), time::Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_next_delay_retry_after() {
+        let mut sched = scheduler();
+        let status = StatusError {
+            url: "http://localhost/v1/pre-reboot".to_string(),
+            status: hyper::StatusCode::SERVICE_UNAVAILABLE,
+            retry_after: Some(time::Duration::from_secs(500)),
+        };
+        sched.record_outcome(&Err(Subsystem::Strategy.tag(status.into())));
+
+        // Retry-After is honored, even beyond the backoff cap.
+        let delay = sched.next_delay();
+        assert!(delay > time::Duration::from_secs(490), "{:?}", delay);
+        assert!(delay <= time::Duration::from_secs(500), "{:?}", delay);
+
+        // Without a hint, the next failure falls back to backoff.
+        sched.record_outcome(&remote_error(Subsystem::Strategy));
+        assert_eq!(sched.next_delay(), time::Duration::from_secs(40));
+    }
+
+    #[test]
+    fn test_next_delay_splay() {
+        let cfg = AgentInput {
+            refresh_period_secs: Some(10),
+            ..AgentInput::default()
+        };
+        let sched = Scheduler::try_from_config(&cfg).unwrap();
+
+        // Splay defaults to half the refresh period.
+        for _ in 0..100 {
+            let delay = sched.next_delay();
+            assert!(delay >= time::Duration::from_secs(10), "{:?}", delay);
+            assert!(delay < time::Duration::from_secs(15), "{:?}", delay);
+        }
+    }
+}
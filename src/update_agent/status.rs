@@ -0,0 +1,249 @@
+//! Agent status reporting.
+
+use super::agent::{UpdateAgent, UpdateAgentState};
+use super::persist;
+use crate::rpm_ostree::TransactionProgress;
+use actix::prelude::*;
+use failure::Error;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+/// Operation currently in progress.
+#[derive(Clone, Debug)]
+pub(crate) struct InFlight {
+    /// Operation name.
+    pub(crate) operation: &'static str,
+    /// Start time of the operation.
+    pub(crate) started: time::SystemTime,
+    /// Latest rpm-ostree transaction progress, if any.
+    pub(crate) progress: Option<TransactionProgress>,
+}
+
+/// Outcome of the last update, verified after reboot.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct UpdateOutcome {
+    /// Version the update was expected to land on.
+    pub(crate) target_version: String,
+    /// Version actually booted after reboot.
+    pub(crate) booted_version: String,
+    /// Whether the target version was booted.
+    pub(crate) success: bool,
+    /// Finalization time, in seconds since UNIX epoch.
+    pub(crate) finalized_at: u64,
+    /// Verification time, in seconds since UNIX epoch.
+    pub(crate) verified_at: u64,
+}
+
+/// Snapshot of the agent status.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct AgentStatus {
+    /// Current state-machine state.
+    pub(crate) state: &'static str,
+    /// Target release version, if any.
+    pub(crate) target_version: Option<String>,
+    /// Booted version, as of the last update check.
+    pub(crate) booted_version: Option<String>,
+    /// Time of the last update check, in seconds since UNIX epoch.
+    pub(crate) last_check_at: Option<u64>,
+    /// Operation in progress, if any.
+    pub(crate) in_flight: Option<InFlightStatus>,
+    /// Time spent waiting for reboot, in seconds, if any.
+    pub(crate) reboot_pending_secs: Option<u64>,
+    /// Outcome of the last update, if any.
+    pub(crate) last_update: Option<UpdateOutcome>,
+    /// Failed attempts for the current state, if retrying.
+    pub(crate) retry: Option<RetryStatus>,
+    /// Why the target release was abandoned, if it was.
+    pub(crate) abandon_reason: Option<String>,
+    /// Whether updates are paused by an operator.
+    pub(crate) paused: bool,
+}
+
+/// Latest agent status, published by the agent on changes.
+///
+/// This lets consumers outside of the actix system, like the DBus
+/// service, follow the agent without polling it.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct StatusWatch {
+    latest: Arc<Mutex<Option<AgentStatus>>>,
+}
+
+impl StatusWatch {
+    /// Publish a status snapshot, replacing any unconsumed one.
+    pub(crate) fn publish(&self, status: AgentStatus) {
+        let mut latest = self.latest.lock().expect("poisoned lock");
+        *latest = Some(status);
+    }
+
+    /// Take the latest snapshot, if any was published since the last call.
+    pub(crate) fn take(&self) -> Option<AgentStatus> {
+        self.latest.lock().expect("poisoned lock").take()
+    }
+}
+
+/// Failed attempts against a retry budget.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct RetryStatus {
+    pub(crate) attempts: u32,
+    pub(crate) max_attempts: u32,
+}
+
+/// Serializable details of an operation in progress.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct InFlightStatus {
+    pub(crate) operation: &'static str,
+    /// Start time, in seconds since UNIX epoch.
+    pub(crate) started_at: u64,
+    /// Elapsed time, in seconds.
+    pub(crate) elapsed_secs: u64,
+    /// Latest rpm-ostree transaction progress, if any.
+    pub(crate) progress: Option<TransactionProgress>,
+}
+
+impl From<&InFlight> for InFlightStatus {
+    fn from(op: &InFlight) -> Self {
+        let started_at = op
+            .started
+            .duration_since(time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let elapsed_secs = op.started.elapsed().map(|d| d.as_secs()).unwrap_or(0);
+        Self {
+            operation: op.operation,
+            started_at,
+            elapsed_secs,
+            progress: op.progress.clone(),
+        }
+    }
+}
+
+impl UpdateAgentState {
+    /// Labels of all states, see `label`.
+    pub(crate) const LABELS: [&'static str; 11] = [
+        "start",
+        "initialization",
+        "reported-steady",
+        "no-new-update",
+        "staging-failed",
+        "update-staged",
+        "finalization-failed",
+        "reboot-pending",
+        "health-check",
+        "rollback-pending",
+        "abandoned",
+    ];
+
+    /// Short label for this state.
+    pub(crate) fn label(&self) -> &'static str {
+        match self {
+            UpdateAgentState::StartState => "start",
+            UpdateAgentState::Initialization => "initialization",
+            UpdateAgentState::ReportedSteady => "reported-steady",
+            UpdateAgentState::NoNewUpdate => "no-new-update",
+            UpdateAgentState::StagingFailed(..) => "staging-failed",
+            UpdateAgentState::UpdateStaged(_) => "update-staged",
+            UpdateAgentState::FinalizationFailed(..) => "finalization-failed",
+            UpdateAgentState::RebootPending(..) => "reboot-pending",
+            UpdateAgentState::HealthCheck(..) => "health-check",
+            UpdateAgentState::RollbackPending(..) => "rollback-pending",
+            UpdateAgentState::Abandoned(..) => "abandoned",
+        }
+    }
+
+    /// Release this state is about, if any.
+    pub(crate) fn target(&self) -> Option<&libcincinnati::Release> {
+        match self {
+            UpdateAgentState::StagingFailed(r, _)
+            | UpdateAgentState::UpdateStaged(r)
+            | UpdateAgentState::FinalizationFailed(r, _)
+            | UpdateAgentState::RebootPending(r, _, _)
+            | UpdateAgentState::HealthCheck(r, _)
+            | UpdateAgentState::RollbackPending(r, _)
+            | UpdateAgentState::Abandoned(r, _) => Some(r),
+            _ => None,
+        }
+    }
+
+    /// Failed attempts so far, for retrying states.
+    pub(crate) fn attempts(&self) -> Option<u32> {
+        match self {
+            UpdateAgentState::StagingFailed(_, n) | UpdateAgentState::FinalizationFailed(_, n) => {
+                Some(*n)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// UpdateAgent request: get current status.
+pub(crate) struct GetStatus {}
+
+impl Message for GetStatus {
+    type Result = Result<AgentStatus, Error>;
+}
+
+impl Handler<GetStatus> for UpdateAgent {
+    type Result = Result<AgentStatus, Error>;
+
+    fn handle(&mut self, _msg: GetStatus, _ctx: &mut Self::Context) -> Self::Result {
+        Ok(self.status())
+    }
+}
+
+impl UpdateAgent {
+    /// Snapshot of the current status.
+    pub(crate) fn status(&self) -> AgentStatus {
+        AgentStatus {
+            state: self.state.label(),
+            target_version: self.state.target().map(|r| r.version().to_string()),
+            booted_version: self.booted_version.clone(),
+            last_check_at: self.last_check.map(persist::epoch_secs),
+            in_flight: self.in_flight.as_ref().map(InFlightStatus::from),
+            reboot_pending_secs: match self.state {
+                UpdateAgentState::RebootPending(_, since, _) => {
+                    Some(since.elapsed().map(|d| d.as_secs()).unwrap_or(0))
+                }
+                _ => None,
+            },
+            last_update: self.last_update.clone(),
+            retry: match self.state {
+                UpdateAgentState::StagingFailed(_, attempts) => Some(RetryStatus {
+                    attempts,
+                    max_attempts: self.scheduler.max_staging_attempts(),
+                }),
+                UpdateAgentState::FinalizationFailed(_, attempts) => Some(RetryStatus {
+                    attempts,
+                    max_attempts: self.scheduler.max_finalization_attempts(),
+                }),
+                _ => None,
+            },
+            abandon_reason: match self.state {
+                UpdateAgentState::Abandoned(_, ref reason) => Some(reason.clone()),
+                _ => None,
+            },
+            paused: self.paused,
+        }
+    }
+
+    /// Publish the current status to watchers.
+    pub(crate) fn publish_status(&self) {
+        self.status_watch.publish(self.status());
+    }
+}
+
+impl Handler<TransactionProgress> for UpdateAgent {
+    type Result = ();
+
+    fn handle(&mut self, msg: TransactionProgress, _ctx: &mut Self::Context) -> Self::Result {
+        match msg {
+            TransactionProgress::Message { .. } => info!("rpm-ostree: {}", msg),
+            TransactionProgress::Stalled { .. } => warn!("rpm-ostree: {}", msg),
+            _ => debug!("rpm-ostree: {}", msg),
+        };
+
+        match self.in_flight {
+            Some(ref mut op) => op.progress = Some(msg),
+            None => trace!("progress received with no operation in flight"),
+        }
+    }
+}